@@ -1,26 +1,99 @@
-use clap::Parser;
-use rvsim::Program;
-use std::{error::Error, path::PathBuf};
+use clap::{Parser, ValueEnum};
+use rvsim::{Endian, Program};
+use std::{
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path of the file to be assembled
+    /// Path of the file to be assembled, `-` reads it from stdin
     path: PathBuf,
 
     /// Path of the file to be written to.
     /// If not specified, the output will be written to stdout
     #[arg(short, long)]
     out: Option<PathBuf>,
+
+    /// Byte order of the words written to the output file
+    #[arg(long, value_enum, default_value_t = EndianArg::Little)]
+    endian: EndianArg,
+
+    /// Disassemble the output and compare it with the source instead
+    #[arg(short, long)]
+    check: bool,
+
+    /// Print every word with its address and source line
+    #[arg(long, conflicts_with = "check")]
+    listing: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum EndianArg {
+    Little,
+    Big,
+}
+
+impl From<EndianArg> for Endian {
+    fn from(endian: EndianArg) -> Self {
+        match endian {
+            EndianArg::Little => Endian::Little,
+            EndianArg::Big => Endian::Big,
+        }
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+// print errors with their message rather than their `Debug` form.
+fn main() {
+    if let Err(err) = try_main() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     let path = args.path;
-    let program = Program::from_file(&path)?;
+    let program = if path == Path::new("-") {
+        Program::from_buffer(io::stdin().lock())
+    } else {
+        Program::from_file(&path)
+    }?;
+
+    if args.check {
+        let mut mismatches = 0;
+        for line in program.listing() {
+            println!(
+                "{:08x}: {:08x}  {:<32}{}{}",
+                line.addr,
+                line.word,
+                line.source,
+                line.decoded,
+                if line.matches { "" } else { "  <- mismatch" }
+            );
+            if !line.matches {
+                mismatches += 1;
+            }
+        }
+
+        if mismatches > 0 {
+            eprintln!("{}: {} mismatches", path.display(), mismatches);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.listing {
+        print!("{}", program.listing_text());
+        if args.out.is_none() {
+            return Ok(());
+        }
+    }
 
     if let Some(out) = args.out {
-        program.write_file(&out.as_path())?;
+        program.write_file_with_endian(&out.as_path(), args.endian.into())?;
     } else {
         program.print_stdout();
     }
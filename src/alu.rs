@@ -0,0 +1,192 @@
+/// The operation `alu` performs, decoded from an instruction's opcode and
+/// funct fields.
+#[derive(Clone, PartialEq, Debug, Copy)]
+pub enum AluType {
+    Add = 0,
+    Sll = 1,
+    Slt = 2,
+    /// Unsigned `a < b`. `sltiu` sign-extends its 12-bit immediate first,
+    /// so `sltiu rd, rs1, -1` compares against 0xffffffff.
+    Sltu = 3,
+    Xor = 4,
+    Srl = 5,
+    Or = 6,
+    And = 7,
+    Mul = 8,
+    Mulh = 9,
+    Mulhsu = 10,
+    Mulhu = 11,
+    Sub = 12,
+    Sra = 13,
+    Bsel = 15,
+    Div = 16,
+    Divu = 17,
+    Rem = 18,
+    Remu = 19,
+}
+
+impl TryFrom<u32> for AluType {
+    // the code itself, if no operation has it.
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, u32> {
+        match value {
+            0 => Ok(AluType::Add),
+            1 => Ok(AluType::Sll),
+            2 => Ok(AluType::Slt),
+            3 => Ok(AluType::Sltu),
+            4 => Ok(AluType::Xor),
+            5 => Ok(AluType::Srl),
+            6 => Ok(AluType::Or),
+            7 => Ok(AluType::And),
+            8 => Ok(AluType::Mul),
+            9 => Ok(AluType::Mulh),
+            10 => Ok(AluType::Mulhsu),
+            11 => Ok(AluType::Mulhu),
+            12 => Ok(AluType::Sub),
+            13 => Ok(AluType::Sra),
+            15 => Ok(AluType::Bsel),
+            16 => Ok(AluType::Div),
+            17 => Ok(AluType::Divu),
+            18 => Ok(AluType::Rem),
+            19 => Ok(AluType::Remu),
+            _ => Err(value),
+        }
+    }
+}
+
+/// `a op b`, both operands and the result as raw register values.
+pub fn alu(a: u32, b: u32, op: AluType) -> u32 {
+    match op {
+        AluType::Add => a.wrapping_add(b),
+        AluType::Sub => a.wrapping_sub(b),
+        AluType::And => a & b,
+        AluType::Or => a | b,
+        AluType::Xor => a ^ b,
+        // only the low 5 bits are the shift amount, which also drops the
+        // funct7 bits `srai` carries in its immediate.
+        AluType::Sll => a.wrapping_shl(b),
+        AluType::Srl => a.wrapping_shr(b),
+        AluType::Sra => (a as i32).wrapping_shr(b) as u32,
+        AluType::Slt => ((a as i32) < (b as i32)) as u32,
+        AluType::Sltu => (a < b) as u32,
+        AluType::Mul => a.wrapping_mul(b),
+        AluType::Mulh => ((a as i32 as i64).wrapping_mul(b as i32 as i64) >> 32) as u32,
+        AluType::Mulhsu => ((a as i32 as i64).wrapping_mul(b as i64) >> 32) as u32,
+        AluType::Mulhu => ((a as u64).wrapping_mul(b as u64) >> 32) as u32,
+        // division by zero and the `i32::MIN / -1` overflow don't trap, they
+        // give the results the spec defines.
+        AluType::Div if b == 0 => u32::MAX,
+        AluType::Div => (a as i32).wrapping_div(b as i32) as u32,
+        AluType::Divu if b == 0 => u32::MAX,
+        AluType::Divu => a / b,
+        AluType::Rem if b == 0 => a,
+        AluType::Rem => (a as i32).wrapping_rem(b as i32) as u32,
+        AluType::Remu if b == 0 => a,
+        AluType::Remu => a % b,
+        AluType::Bsel => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_alu() {
+        assert_eq!(alu(1, 2, AluType::Add), 3);
+        assert_eq!(alu(1, 2, AluType::Sub), 0xffff_ffff);
+        assert_eq!(alu(1, 2, AluType::And), 0);
+        assert_eq!(alu(1, 2, AluType::Or), 3);
+        assert_eq!(alu(1, 2, AluType::Xor), 3);
+        assert_eq!(alu(1, 2, AluType::Sll), 4);
+        assert_eq!(alu(1, 2, AluType::Srl), 0);
+        assert_eq!(alu(1, 2, AluType::Sra), 0);
+        assert_eq!(alu(1, 2, AluType::Slt), 1);
+        assert_eq!(alu(1, 2, AluType::Sltu), 1);
+        assert_eq!(alu(1, 2, AluType::Mul), 2);
+        assert_eq!(alu(0x7fff_ffff, 4, AluType::Mulh), 1);
+        assert_eq!(alu(0x7fff_ffff, 4, AluType::Mulhu), 1);
+        assert_eq!(alu(0xffff_ffff, 4, AluType::Mulhsu), 0xffff_ffff);
+        assert_eq!(alu(-7i32 as u32, 2, AluType::Div), -3i32 as u32);
+        assert_eq!(alu(7, 0, AluType::Div), u32::MAX);
+        assert_eq!(alu(0x8000_0000, u32::MAX, AluType::Div), 0x8000_0000);
+        assert_eq!(alu(7, 2, AluType::Divu), 3);
+        assert_eq!(alu(7, 0, AluType::Divu), u32::MAX);
+        assert_eq!(alu(-7i32 as u32, 2, AluType::Rem), -1i32 as u32);
+        assert_eq!(alu(7, 0, AluType::Rem), 7);
+        assert_eq!(alu(0x8000_0000, u32::MAX, AluType::Rem), 0);
+        assert_eq!(alu(7, 2, AluType::Remu), 1);
+        assert_eq!(alu(7, 0, AluType::Remu), 7);
+        assert_eq!(alu(1, 2, AluType::Bsel), 2);
+    }
+
+    #[test]
+    fn test_alu_signed() {
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Add), 0);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Sub), 0xffff_fffe);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Sll), 0xffff_fffe);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Srl), 0x7fff_ffff);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Sra), 0xffff_ffff);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Slt), 1);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Sltu), 0);
+        assert_eq!(alu(0xffff_ffff, 2, AluType::Mul), 0xffff_fffe);
+        assert_eq!(alu(0xffff_ffff, 2, AluType::Mulh), 0xffff_ffff);
+        assert_eq!(alu(0xffff_ffff, 2, AluType::Mulhu), 1);
+        assert_eq!(alu(0xffff_ffff, 1, AluType::Bsel), 1);
+    }
+
+    #[test]
+    fn test_alu_shift_amount() {
+        assert_eq!(alu(1, 33, AluType::Sll), 2);
+        assert_eq!(alu(4, 33, AluType::Srl), 2);
+        assert_eq!(alu(0x8000_0000, 33, AluType::Sra), 0xc000_0000);
+        assert_eq!(alu(1, 0xffff_ffff, AluType::Sll), 0x8000_0000);
+        // 32 and 63 shift by 0 and 31.
+        for op in [AluType::Sll, AluType::Srl, AluType::Sra] {
+            assert_eq!(alu(0x8000_0001, 32, op), 0x8000_0001);
+        }
+        assert_eq!(alu(1, 63, AluType::Sll), 0x8000_0000);
+        assert_eq!(alu(0x8000_0000, 63, AluType::Srl), 1);
+        assert_eq!(alu(0x8000_0000, 63, AluType::Sra), 0xffff_ffff);
+
+        // srai x1, x1, 4 => the immediate is 0x404
+        let inst = Instruction::from_binary(0x4040d093).unwrap();
+        assert_eq!(inst.alu_op(), AluType::Sra);
+        assert_eq!(alu(0xffff_ff00, inst.imm(), inst.alu_op()), 0xffff_fff0);
+    }
+
+    #[test]
+    fn test_sltiu() {
+        // sltiu x1, x2, -1: every value but 0xffffffff is below it.
+        let inst = Instruction::from_binary(0xfff13093).unwrap();
+        assert_eq!(inst.imm(), 0xffff_ffff);
+        assert_eq!(inst.alu_op(), AluType::Sltu);
+        assert_eq!(alu(0, inst.imm(), inst.alu_op()), 1);
+        assert_eq!(alu(0xffff_fffe, inst.imm(), inst.alu_op()), 1);
+        assert_eq!(alu(0xffff_ffff, inst.imm(), inst.alu_op()), 0);
+
+        // sltiu x1, x0, 1, and `seqz` is `sltiu rd, rs1, 1`.
+        let inst = Instruction::from_binary(0x00103093).unwrap();
+        assert_eq!(alu(0, inst.imm(), inst.alu_op()), 1);
+        assert_eq!(alu(5, inst.imm(), inst.alu_op()), 0);
+    }
+
+    #[test]
+    fn test_mulhsu() {
+        // mulhsu x1, x2, x3
+        let inst = Instruction::from_binary(0x023120b3).unwrap();
+        assert_eq!(inst.alu_op(), AluType::Mulhsu);
+
+        // -2 * 0x80000000 = -2^32, only rs1 is signed.
+        assert_eq!(alu(0xffff_fffe, 0x8000_0000, AluType::Mulhsu), 0xffff_ffff);
+        assert_eq!(alu(0xffff_fffe, 0x8000_0000, AluType::Mulh), 1);
+        assert_eq!(alu(0xffff_fffe, 0x8000_0000, AluType::Mulhu), 0x7fff_ffff);
+        assert_eq!(alu(0xffff_fffe, 0x8000_0000, AluType::Mul), 0);
+
+        // -1 * 0xffffffff = -0xffffffff
+        assert_eq!(alu(0xffff_ffff, 0xffff_ffff, AluType::Mulhsu), 0xffff_ffff);
+        assert_eq!(alu(0xffff_ffff, 0xffff_ffff, AluType::Mul), 1);
+    }
+}
@@ -1,83 +1,304 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
     fs::File,
     io::{BufRead, BufReader, Write},
     path::Path,
 };
 
+use super::{error::RvError, instruction::Instruction};
 use lazy_static::lazy_static;
-use regex::Regex;
+use regex::{Captures, Regex};
 
 #[derive(Default)]
 pub struct Program {
     mem: Vec<u32>,
     inst_name: HashMap<u32, String>,
+    // the source index and 1-based line of each instruction.
+    inst_line: HashMap<u32, (usize, usize)>,
     entry_addr: u32,
+    bss_size: u32,
+    symbol: HashMap<String, u32>,
+    // the `%hi`/`%lo` immediate and the absolute address it holds, by
+    // instruction address, to move them when loaded elsewhere than 0.
+    relocs: HashMap<u32, (String, u32)>,
+    // the names given to `from_buffers`, by source index.
+    files: Vec<String>,
+}
+
+/// Byte order of the words in an output file.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// An assembled instruction word next to the source line it came from.
+pub struct ListingLine {
+    pub addr: u32,
+    pub word: u32,
+    pub source: String,
+    pub decoded: String,
+    /// Whether `decoded` has the same mnemonic and operands as `source`.
+    pub matches: bool,
 }
 
 impl Program {
-    pub fn from_file(path: &Path) -> Result<Self, String> {
-        let file = File::open(path).map_err(|e| e.to_string())?;
+    pub fn from_file(path: &Path) -> Result<Self, RvError> {
+        let file = File::open(path).map_err(|e| RvError::Io(e.to_string()))?;
         let reader = BufReader::new(file);
 
         Self::from_buffer(reader)
     }
 
-    pub(crate) fn from_buffer<T>(reader: T) -> Result<Self, String>
+    /// Read back an image written by `write_file_with_endian`. There is no
+    /// source to go with it, so the entry is address 0 and there are no
+    /// symbols.
+    pub fn from_binary(bytes: &[u8], endian: Endian) -> Result<Self, RvError> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(RvError::InvalidImage(bytes.len()));
+        }
+
+        let mem = bytes
+            .chunks_exact(4)
+            .map(|chunk| {
+                let word = chunk.try_into().unwrap();
+                match endian {
+                    Endian::Little => u32::from_le_bytes(word),
+                    Endian::Big => u32::from_be_bytes(word),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            mem,
+            inst_name: HashMap::new(),
+            inst_line: HashMap::new(),
+            entry_addr: 0,
+            bss_size: 0,
+            symbol: HashMap::new(),
+            relocs: HashMap::new(),
+            files: Vec::new(),
+        })
+    }
+
+    /// Assemble `src` and return only the encoded words.
+    pub fn assemble_str(src: &str) -> Result<Vec<u32>, RvError> {
+        Self::from_buffer(src.as_bytes()).map(|program| program.mem)
+    }
+
+    /// Assemble the source read from `reader`, e.g. stdin.
+    pub fn from_buffer<T>(reader: T) -> Result<Self, RvError>
     where
         T: BufRead,
     {
-        let buf = reader
-            .lines()
-            .map(|l| l.unwrap().trim().to_string())
-            .collect::<Vec<_>>();
+        let buf = read_lines(reader)?;
+        Self::assemble(&[buf]).map_err(|(line, msg)| RvError::Assemble {
+            line: line.map(|(_, line)| line),
+            msg,
+        })
+    }
 
-        let mut mem = Vec::with_capacity(1024);
-        let mut inst_name = HashMap::new();
+    /// Assemble several files into one image, see `from_buffers`.
+    pub fn from_files(paths: &[&Path]) -> Result<Self, RvError> {
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in paths {
+            let file = File::open(path).map_err(|e| RvError::Io(e.to_string()))?;
+            sources.push((path.display().to_string(), BufReader::new(file)));
+        }
 
-        let main_addr = Self::assembly(&buf, &mut mem, &mut inst_name)?;
+        Self::from_buffers(sources)
+    }
 
-        Ok(Self {
-            mem,
-            inst_name,
-            entry_addr: main_addr,
-        })
+    /// Assemble several sources into one image, like linking them. Their
+    /// sections are appended in order and they share one symbol table, so
+    /// labels can be used across sources but only defined once. `.equ`
+    /// constants stay local to their source. Errors on a line, including an
+    /// undefined label, are prefixed with its source's name, and `line`
+    /// counts from the start of that source.
+    pub fn from_buffers<T>(sources: Vec<(String, T)>) -> Result<Self, RvError>
+    where
+        T: BufRead,
+    {
+        let mut names = Vec::with_capacity(sources.len());
+        let mut bufs = Vec::with_capacity(sources.len());
+        for (name, reader) in sources {
+            names.push(name);
+            bufs.push(read_lines(reader)?);
+        }
+
+        let mut program = Self::assemble(&bufs).map_err(|(line, msg)| match line {
+            Some((file, line)) => RvError::Assemble {
+                line: Some(line),
+                msg: format!("{}: {}", names[file], msg),
+            },
+            None => RvError::Assemble { line: None, msg },
+        })?;
+        program.files = names;
+        Ok(program)
+    }
+
+    // the error comes with the source index and line it was found on.
+    fn assemble(bufs: &[Vec<String>]) -> Result<Self, (Option<(usize, usize)>, String)> {
+        let mut program = Self {
+            mem: Vec::with_capacity(1024),
+            ..Default::default()
+        };
+        let mut line = None;
+
+        program.entry_addr =
+            Self::assembly(bufs, &mut program, &mut line).map_err(|msg| (line, msg))?;
+
+        Ok(program)
     }
 
+    // fill in `program` and return its entry.
     fn assembly(
-        buf: &Vec<String>,
-        mem: &mut Vec<u32>,
-        inst_name: &mut HashMap<u32, String>,
+        bufs: &[Vec<String>],
+        program: &mut Program,
+        // the source and line being assembled, none once all of them are.
+        current_line: &mut Option<(usize, usize)>,
     ) -> Result<u32, String> {
-        let mut symbol: HashMap<String, u32> = HashMap::new();
+        let Program {
+            mem,
+            inst_name,
+            inst_line,
+            bss_size,
+            symbol,
+            relocs,
+            ..
+        } = program;
+        // offset in `.bss` and where each label is defined.
+        let mut bss_symbol: HashMap<String, (u32, (usize, usize))> = HashMap::new();
         let mut empty_labels: HashMap<u32, String> = HashMap::new();
+        // `%hi(label)` and `%lo(label)` immediates, by instruction address.
+        let mut abs_labels: HashMap<u32, (String, String)> = HashMap::new();
         let mut mem_addr: u32 = 0;
+        let mut bss_addr: u32 = 0;
         let mut text_section = false;
         let mut data_section = false;
-        let mut main_label = String::new();
+        let mut bss_section = false;
+        let mut globals: Vec<String> = Vec::new();
+        let mut constants = Constants::default();
+
+        let lines = bufs.iter().enumerate().flat_map(|(file_no, buf)| {
+            buf.iter()
+                .enumerate()
+                .map(move |(line_no, source)| (file_no, line_no, source))
+        });
+        for (file_no, line_no, source) in lines {
+            *current_line = Some((file_no, line_no + 1));
+            // every source starts outside of any section, with its own
+            // constants.
+            if line_no == 0 {
+                text_section = false;
+                data_section = false;
+                bss_section = false;
+                constants = Constants::default();
+            }
+            if source.starts_with("#") || source.is_empty() {
+                continue;
+            }
+
+            if let Some(caps) = EQU_REGEX.captures(source) {
+                let name = caps.name("name").unwrap().as_str();
+                let value = constants.expand(caps.name("value").unwrap().as_str());
+
+                if constants.values.contains_key(name) {
+                    return Err(format!("duplicate constant: {}", name));
+                }
+                if constants.used.contains(name) {
+                    return Err(format!("constant {} is used before its definition", name));
+                }
 
-        for line in buf {
-            if line.starts_with("#") || line.is_empty() {
+                let value = parse_imm(&value)? as i32;
+                constants.values.insert(name.to_string(), value);
                 continue;
             }
 
-            if line.starts_with(".globl") {
-                main_label = line.split_whitespace().nth(1).unwrap().to_owned();
+            let line = source;
+
+            if let Some(names) = line.strip_prefix(".globl") {
+                globals.extend(
+                    names
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|name| !name.is_empty())
+                        .map(|name| name.to_owned()),
+                );
             }
 
+            // every section is appended to the one image, so switching back
+            // and forth keeps labels at their place in it.
+            debug_assert_eq!(mem_addr as usize, mem.len() * 4);
+
             if line.starts_with(".text") {
                 text_section = true;
                 data_section = false;
+                bss_section = false;
                 continue;
             }
 
             if line.starts_with(".data") {
                 data_section = true;
                 text_section = false;
+                bss_section = false;
+                continue;
+            }
+
+            if line.starts_with(".bss") {
+                bss_section = true;
+                text_section = false;
+                data_section = false;
+                continue;
+            }
+
+            // only `.text` holds instructions and `.bss` reserves zeroed space,
+            // every other section (`.rodata`, ...) is laid out like `.data`.
+            if line.starts_with(".section") {
+                let name = line.split_whitespace().nth(1).unwrap_or_default();
+                text_section = name.starts_with(".text");
+                bss_section = name.starts_with(".bss");
+                data_section = !text_section && !bss_section;
+                continue;
+            }
+
+            // `.comm` reserves space in `.bss` whatever the current section is.
+            if let Some(caps) = COMM_REGEX.captures(line) {
+                let label = caps.name("label").unwrap().as_str();
+                let size = parse_imm(&constants.expand(caps.name("size").unwrap().as_str()))?;
+                if bss_symbol
+                    .insert(label.to_string(), (bss_addr, (file_no, line_no + 1)))
+                    .is_some()
+                {
+                    return Err(format!("duplicate label: {}", label));
+                }
+                bss_addr += size.next_multiple_of(4);
                 continue;
             }
 
+            if bss_section {
+                if let Some(caps) = LABEL_REGEX.captures(line) {
+                    let label = caps.name("label").unwrap().as_str();
+                    if bss_symbol
+                        .insert(label.to_string(), (bss_addr, (file_no, line_no + 1)))
+                        .is_some()
+                    {
+                        return Err(format!("duplicate label: {}", label));
+                    }
+                }
+
+                if let Some(caps) = BSS_REGEX.captures(line) {
+                    let data = parse_imm(&constants.expand(caps.name("data").unwrap().as_str()))?;
+                    match caps.name("type").unwrap().as_str() {
+                        "align" => {
+                            let align = alignment(data, line)?;
+                            bss_addr = bss_addr.next_multiple_of(align.max(4));
+                        }
+                        _ => bss_addr += data.next_multiple_of(4),
+                    }
+                }
+            }
+
             if text_section {
                 if let Some(caps) = LABEL_REGEX.captures(line) {
                     let label = caps.name("label").unwrap().as_str();
@@ -86,13 +307,23 @@ impl Program {
                     }
                 }
 
+                let mut matched = false;
+                let mut mismatch = None;
                 for (as_type, regex) in INSTRUCTION_REGEX.iter() {
                     if let Some(caps) = regex.captures(line) {
-                        inst_name.insert(mem_addr, line.to_string());
                         let op = caps.name("op").unwrap().as_str();
                         let opcode = OPCODE_MAP
                             .get(op)
                             .ok_or(format!("invalid opcode: {} in {}", op, line))?;
+                        // the patterns overlap, so a malformed line may match
+                        // one meant for other instructions. Keep looking.
+                        if !valid_form(op, *opcode, as_type) {
+                            mismatch.get_or_insert(op);
+                            continue;
+                        }
+                        matched = true;
+                        inst_name.insert(mem_addr, source.to_string());
+                        inst_line.insert(mem_addr, (file_no, line_no + 1));
 
                         let instruction = match as_type {
                             AssemblyType::RdRs1Rs2 => {
@@ -119,7 +350,22 @@ impl Program {
                                 let rs1 = parse_reg_name(rs1)
                                     .ok_or(format!("invalid register name: {} in {}", rs1, line))?;
 
-                                let imm = parse_imm(imm)?;
+                                let imm = if let Some(reloc) = parse_reloc(imm) {
+                                    abs_labels.insert(mem_addr, reloc);
+                                    0
+                                } else if ["slli", "srli", "srai"].contains(&op) {
+                                    // the upper bits of a shift amount select the shift type.
+                                    let imm = parse_imm(&constants.expand(imm))?;
+                                    if imm > 31 {
+                                        return Err(format!(
+                                            "shift amount {} out of range 0-31 in {}",
+                                            imm as i32, line
+                                        ));
+                                    }
+                                    imm
+                                } else {
+                                    check_imm(parse_imm(&constants.expand(imm))?, 12, line)?
+                                };
 
                                 opcode | (rd << 7) | (rs1 << 15) | (imm << 20)
                             }
@@ -133,7 +379,12 @@ impl Program {
                                 let rs1 = parse_reg_name(rs1)
                                     .ok_or(format!("invalid register name: {} in {}", rs1, line))?;
 
-                                let imm = parse_imm(imm)?;
+                                let imm = if let Some(reloc) = parse_reloc(imm) {
+                                    abs_labels.insert(mem_addr, reloc);
+                                    0
+                                } else {
+                                    check_imm(parse_imm(&constants.expand(imm))?, 12, line)?
+                                };
 
                                 if ["sb", "sh", "sw"].contains(&op) {
                                     opcode
@@ -168,7 +419,17 @@ impl Program {
 
                                 empty_labels.insert(mem_addr, label.to_owned());
 
-                                opcode | (rd << 7)
+                                if op == "la" {
+                                    // la rd, label => auipc rd, %hi(label); addi rd, rd, %lo(label)
+                                    mem.push(opcode | (rd << 7));
+                                    mem_addr += 4;
+                                    inst_name.insert(mem_addr, source.to_string());
+                                    inst_line.insert(mem_addr, (file_no, line_no + 1));
+
+                                    OPCODE_MAP["addi"] | (rd << 7) | (rd << 15)
+                                } else {
+                                    opcode | (rd << 7)
+                                }
                             }
 
                             AssemblyType::RdImm => {
@@ -178,11 +439,85 @@ impl Program {
                                 let rd = parse_reg_name(rd)
                                     .ok_or(format!("invalid register name: {} in {}", rd, line))?;
 
-                                let imm = parse_imm(imm)?;
+                                let imm = if let Some(reloc) = parse_reloc(imm) {
+                                    abs_labels.insert(mem_addr, reloc);
+                                    0
+                                } else if ["lui", "auipc"].contains(&op) {
+                                    // unsigned values of 20 bits are accepted too
+                                    let value = parse_number(&constants.expand(imm))?;
+                                    if !(-(1 << 19)..1 << 20).contains(&value) {
+                                        return Err(format!(
+                                            "immediate {} doesn't fit in 20 bits in {}",
+                                            value, line
+                                        ));
+                                    }
+                                    value as u32
+                                } else {
+                                    check_imm(parse_imm(&constants.expand(imm))?, 12, line)?
+                                };
+
+                                // `lw rd, imm` and `sw rs2, imm` address `imm(x0)`
+                                if ["sb", "sh", "sw"].contains(&op) {
+                                    opcode
+                                        | (rd << 20)
+                                        | ((imm & 0x1f) << 7)
+                                        | ((imm & 0xfe0) << 20)
+                                } else if ["lb", "lh", "lw", "lbu", "lhu"].contains(&op) {
+                                    opcode | (rd << 7) | (imm << 20)
+                                } else {
+                                    // the immediate is the upper 20 bits of the value
+                                    opcode | (rd << 7) | (imm << 12)
+                                }
+                            }
+                            AssemblyType::Label => {
+                                let label = caps.name("label").unwrap().as_str();
 
-                                opcode | (rd << 7) | (imm << 20)
+                                // call label => auipc ra, %hi(label); jalr ra, %lo(label)(ra)
+                                // tail label => auipc t1, %hi(label); jalr x0, %lo(label)(t1)
+                                let (rd, rs) = if op == "call" { (1, 1) } else { (0, 6) };
+
+                                empty_labels.insert(mem_addr, label.to_owned());
+                                mem.push(opcode | (rs << 7));
+                                mem_addr += 4;
+                                inst_name.insert(mem_addr, source.to_string());
+                                inst_line.insert(mem_addr, (file_no, line_no + 1));
+
+                                OPCODE_MAP["jalr"] | (rd << 7) | (rs << 15)
                             }
                             AssemblyType::OnlyOp => opcode.clone(),
+                            AssemblyType::Csr => {
+                                let rd = caps.name("rd").unwrap().as_str();
+                                let csr = caps.name("csr").unwrap().as_str();
+
+                                let rd = parse_reg_name(rd)
+                                    .ok_or(format!("invalid register name: {} in {}", rd, line))?;
+                                let csr = parse_csr(csr)
+                                    .or_else(|| parse_csr(&constants.expand(csr)))
+                                    .ok_or(format!("invalid csr: {} in {}", csr, line))?;
+
+                                // csrr rd, csr => csrrs rd, csr, x0
+                                let src = match caps.name("src") {
+                                    Some(src) if op.ends_with('i') => {
+                                        let uimm = parse_number(&constants.expand(src.as_str()))?;
+                                        if !(0..=31).contains(&uimm) {
+                                            return Err(format!(
+                                                "immediate {} out of range 0-31 in {}",
+                                                uimm, line
+                                            ));
+                                        }
+                                        uimm as u32
+                                    }
+                                    Some(src) => parse_reg_name(src.as_str()).ok_or(format!(
+                                        "invalid register name: {} in {}",
+                                        src.as_str(),
+                                        line
+                                    ))?,
+                                    None if op == "csrr" => 0,
+                                    None => return Err(format!("missing operand in {}", line)),
+                                };
+
+                                opcode | (rd << 7) | (src << 15) | (csr << 20)
+                            }
                         };
 
                         mem.push(instruction);
@@ -192,6 +527,27 @@ impl Program {
                         break;
                     }
                 }
+
+                // anything left besides a label, a directive or a comment
+                // should have been an instruction.
+                let code = line.split('#').next().unwrap_or_default();
+                let code = LABEL_REGEX.replace(code, "");
+                let code = code.trim();
+                if let (false, Some(op)) = (matched, mismatch) {
+                    return Err(format!(
+                        "line {}: invalid operands for {}: {}",
+                        line_no + 1,
+                        op,
+                        code
+                    ));
+                }
+                if !matched && !code.is_empty() && !code.starts_with('.') {
+                    return Err(format!(
+                        "line {}: unrecognized instruction: {}",
+                        line_no + 1,
+                        code
+                    ));
+                }
             }
 
             if data_section {
@@ -208,9 +564,12 @@ impl Program {
                         let data = caps.name("data").unwrap().as_str();
 
                         match data_type {
-                            "string" => {
-                                let mut bytes = data.as_bytes().to_vec();
-                                bytes.push(0);
+                            "string" | "ascii" => {
+                                let mut bytes =
+                                    unescape(data).map_err(|e| format!("{} in {}", e, line))?;
+                                if data_type == "string" {
+                                    bytes.push(0);
+                                }
                                 let mut size = bytes.len();
                                 if size % 4 != 0 {
                                     size += 4 - size % 4;
@@ -218,7 +577,7 @@ impl Program {
                                 bytes.resize(size, 0);
                                 let mut word = 0;
                                 for i in 0..size {
-                                    word = (word << 8) | (bytes[i] as u32);
+                                    word |= (bytes[i] as u32) << (8 * (i % 4));
                                     if i % 4 == 3 {
                                         mem.push(word);
                                         word = 0;
@@ -228,15 +587,39 @@ impl Program {
                             }
                             "word" => {
                                 for word in data.split_ascii_whitespace() {
-                                    mem.push(parse_imm(word)?);
+                                    mem.push(parse_imm(&constants.expand(word)).map_err(|e| {
+                                        format!("invalid word {}: {} in {}", word, e, line)
+                                    })?);
+                                }
+                                mem_addr += 4 * data.split_whitespace().count() as u32;
+                            }
+                            "float" => {
+                                for float in data.split_ascii_whitespace() {
+                                    let value =
+                                        constants.expand(float).parse::<f32>().map_err(|e| {
+                                            format!("invalid float {}: {} in {}", float, e, line)
+                                        })?;
+                                    mem.push(value.to_bits());
                                 }
                                 mem_addr += 4 * data.split_whitespace().count() as u32;
                             }
                             "byte" => {
                                 let mut bytes = data
                                     .split_ascii_whitespace()
-                                    .map(|b| b.parse::<u8>().unwrap())
-                                    .collect::<Vec<_>>();
+                                    .map(|b| {
+                                        let value =
+                                            parse_number(&constants.expand(b)).map_err(|e| {
+                                                format!("invalid byte {}: {} in {}", b, e, line)
+                                            })?;
+                                        if !(-128..=255).contains(&value) {
+                                            return Err(format!(
+                                                "value out of range: {} in {}",
+                                                b, line
+                                            ));
+                                        }
+                                        Ok(value as u8)
+                                    })
+                                    .collect::<Result<Vec<_>, _>>()?;
                                 let mut size = bytes.len();
                                 if size % 4 != 0 {
                                     size += 4 - size % 4;
@@ -244,7 +627,7 @@ impl Program {
                                 bytes.resize(size, 0);
                                 let mut word = 0;
                                 for i in 0..size {
-                                    word = (word << 8) | (bytes[i] as u32);
+                                    word |= (bytes[i] as u32) << (8 * (i % 4));
                                     if i % 4 == 3 {
                                         mem.push(word);
                                         word = 0;
@@ -255,8 +638,20 @@ impl Program {
                             "half" => {
                                 let mut bytes = data
                                     .split_ascii_whitespace()
-                                    .map(|b| b.parse::<u16>().unwrap())
-                                    .collect::<Vec<_>>();
+                                    .map(|h| {
+                                        let value =
+                                            parse_number(&constants.expand(h)).map_err(|e| {
+                                                format!("invalid half {}: {} in {}", h, e, line)
+                                            })?;
+                                        if !(-32768..=65535).contains(&value) {
+                                            return Err(format!(
+                                                "value out of range: {} in {}",
+                                                h, line
+                                            ));
+                                        }
+                                        Ok(value as u16)
+                                    })
+                                    .collect::<Result<Vec<_>, _>>()?;
                                 let mut size = bytes.len();
                                 if size % 2 != 0 {
                                     size += 2 - size % 2;
@@ -264,7 +659,7 @@ impl Program {
                                 bytes.resize(size, 0);
                                 let mut word = 0;
                                 for i in 0..size {
-                                    word = (word << 16) | (bytes[i] as u32);
+                                    word |= (bytes[i] as u32) << (16 * (i % 2));
                                     if i % 2 == 1 {
                                         mem.push(word);
                                         word = 0;
@@ -272,6 +667,21 @@ impl Program {
                                 }
                                 mem_addr += (size * 2) as u32;
                             }
+                            "zero" | "space" => {
+                                let mut size = parse_imm(&constants.expand(data))?;
+                                if size % 4 != 0 {
+                                    size += 4 - size % 4;
+                                }
+                                mem.resize(mem.len() + size as usize / 4, 0);
+                                mem_addr += size;
+                            }
+                            "align" => {
+                                let align = alignment(parse_imm(&constants.expand(data))?, line)?;
+                                while !mem_addr.is_multiple_of(align) {
+                                    mem.push(0);
+                                    mem_addr += 4;
+                                }
+                            }
                             _ => {
                                 return Err(format!("unknown data type: {}", data_type));
                             }
@@ -281,7 +691,20 @@ impl Program {
             }
         }
 
+        // `.bss` is not part of the image, it lives right after it and is
+        // zeroed when the program is loaded. Errors from here on are on the
+        // line which defines or uses the label.
+        let bss_base = mem.len() as u32 * 4;
+        for (label, (offset, defined)) in bss_symbol {
+            *current_line = Some(defined);
+            if symbol.insert(label.clone(), bss_base + offset).is_some() {
+                return Err(format!("duplicate label: {}", label));
+            }
+        }
+        *bss_size = bss_addr;
+
         for (addr, label) in empty_labels {
+            *current_line = inst_line.get(&addr).copied();
             let offset = (*symbol.get(&label).ok_or(format!(
                 "undefined label {} in {}",
                 label,
@@ -289,11 +712,39 @@ impl Program {
             ))? as i32
                 - addr as i32) as u32;
             let mut inst = mem[addr as usize / 4];
+
+            // branches reach +-4KiB and `jal` +-1MiB, in multiples of 2 bytes.
+            let range = match inst & 0x7f {
+                0x6f => Some(1 << 20),
+                0x17 => None,
+                _ => Some(1 << 12),
+            };
+            if let Some(range) = range {
+                let distance = offset as i32;
+                if distance < -range || distance >= range {
+                    return Err(format!(
+                        "branch target out of range: label '{}' is {} bytes away",
+                        label, distance
+                    ));
+                }
+                if distance % 2 != 0 {
+                    return Err(format!(
+                        "branch target misaligned: label '{}' is {} bytes away",
+                        label, distance
+                    ));
+                }
+            }
+
             if inst & 0x7f == 0x6f {
                 inst |= ((offset & 0x100000) << 11)
                     | ((offset & 0x7fe) << 20)
                     | ((offset & 0x800) << 9)
                     | (offset & 0xff000);
+            } else if inst & 0x7f == 0x17 {
+                // auipc pair, the low part is sign-extended by the second
+                // instruction so carry its sign bit into the high part.
+                inst |= offset.wrapping_add(0x800) & 0xfffff000;
+                mem[addr as usize / 4 + 1] |= (offset & 0xfff) << 20;
             } else {
                 inst |= ((offset & 0x1000) << 19)
                     | ((offset & 0x7e0) << 20)
@@ -303,18 +754,66 @@ impl Program {
             mem[addr as usize / 4] = inst;
         }
 
-        symbol
-            .get(&main_label)
-            .ok_or("program entry not found".to_string())
-            .copied()
+        // absolute addresses, `%lo` is sign-extended by its instruction so
+        // `%hi` carries its sign bit like the auipc pairs.
+        for (addr, (part, label)) in abs_labels {
+            *current_line = inst_line.get(&addr).copied();
+            let value = *symbol.get(&label).ok_or(format!(
+                "undefined label {} in {}",
+                label,
+                inst_name.get(&addr).unwrap()
+            ))?;
+            let inst = &mut mem[addr as usize / 4];
+            *inst = with_abs_imm(*inst, &part, value);
+            relocs.insert(addr, (part, value));
+        }
+        *current_line = None;
+
+        // the entry is `main` or `_start` if either is a defined global,
+        // otherwise the first defined one.
+        let defined = globals
+            .iter()
+            .filter(|name| symbol.contains_key(*name))
+            .collect::<Vec<_>>();
+        let entry = ["main", "_start"]
+            .iter()
+            .find_map(|preferred| defined.iter().find(|name| name.as_str() == *preferred))
+            .or(defined.first());
+
+        // without any `.globl`, a `_start` or `main` label is the entry.
+        match entry {
+            Some(name) => Ok(symbol[*name]),
+            None if globals.is_empty() => ["_start", "main"]
+                .iter()
+                .find_map(|name| symbol.get(*name).copied())
+                .ok_or("program entry not found".to_string()),
+            None => Err(format!(
+                "program entry not found, undefined globals: {}",
+                globals.join(", ")
+            )),
+        }
     }
 
-    // fixme: solve endian problem
+    /// Write the image in little endian, RISC-V's own byte order and the one
+    /// the memory is laid out in, so bytes end up at their addresses.
     pub fn write_file(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.write_file_with_endian(path, Endian::Little)
+    }
+
+    /// Write the image one word after another in the `endian` byte order.
+    pub fn write_file_with_endian(
+        &self,
+        path: &Path,
+        endian: Endian,
+    ) -> Result<(), Box<dyn Error>> {
         let mut file = File::create(path)?;
 
         for word in self.mem.iter() {
-            file.write_all(&word.to_be_bytes())?;
+            let bytes = match endian {
+                Endian::Little => word.to_le_bytes(),
+                Endian::Big => word.to_be_bytes(),
+            };
+            file.write_all(&bytes)?;
         }
         Ok(())
     }
@@ -329,13 +828,271 @@ impl Program {
         &self.mem
     }
 
+    /// The image to load at `base`, with the absolute addresses of the
+    /// `%hi`/`%lo` immediates moved by `base`.
+    pub fn mem_at(&self, base: u32) -> Vec<u32> {
+        let mut mem = self.mem.clone();
+        for (addr, (part, value)) in &self.relocs {
+            let inst = &mut mem[*addr as usize / 4];
+            *inst = with_abs_imm(*inst, part, value.wrapping_add(base));
+        }
+        mem
+    }
+
     pub fn inst_name(&self) -> &HashMap<u32, String> {
         &self.inst_name
     }
 
+    /// Whether the word at `addr` was assembled from `.text`.
+    pub fn is_text(&self, addr: u32) -> bool {
+        self.inst_name.contains_key(&(addr & !3))
+    }
+
+    /// Address of the first instruction assembled from the 1-based `line`
+    /// of the source at index `file`, 0 for a single source.
+    pub fn line_to_address(&self, file: usize, line: usize) -> Option<u32> {
+        self.inst_line
+            .iter()
+            .filter(|(_, l)| **l == (file, line))
+            .map(|(addr, _)| *addr)
+            .min()
+    }
+
+    /// Source index and 1-based line the instruction at `addr` was
+    /// assembled from.
+    pub fn address_to_line(&self, addr: u32) -> Option<(usize, usize)> {
+        self.inst_line.get(&addr).copied()
+    }
+
+    /// Name of the source at index `file`, as given to `from_buffers` or
+    /// `from_files`. A program from a single source has none.
+    pub fn file_name(&self, file: usize) -> Option<&str> {
+        self.files.get(file).map(|name| name.as_str())
+    }
+
+    pub fn symbols(&self) -> &HashMap<String, u32> {
+        &self.symbol
+    }
+
+    pub fn addr_of(&self, label: &str) -> Option<u32> {
+        self.symbol.get(label).copied()
+    }
+
+    /// Address to label map, for disassembly. If several labels share an
+    /// address, the alphabetically first one is kept.
+    pub fn labels(&self) -> BTreeMap<u32, String> {
+        let mut labels: BTreeMap<u32, String> = BTreeMap::new();
+        for (name, &addr) in self.symbol.iter() {
+            match labels.get(&addr) {
+                Some(old) if old <= name => {}
+                _ => {
+                    labels.insert(addr, name.clone());
+                }
+            }
+        }
+        labels
+    }
+
     pub fn entry(&self) -> u32 {
         self.entry_addr
     }
+
+    pub fn text_size(&self) -> u32 {
+        self.inst_name.len() as u32 * 4
+    }
+
+    pub fn data_size(&self) -> u32 {
+        self.mem.len() as u32 * 4 - self.text_size()
+    }
+
+    pub fn bss_size(&self) -> u32 {
+        self.bss_size
+    }
+
+    /// Check the assembled program without running it, returning one
+    /// diagnostic per problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut diagnostics = Vec::new();
+
+        if !self.inst_name.contains_key(&self.entry_addr) {
+            diagnostics.push(format!(
+                "program entry {:08x} is not an instruction",
+                self.entry_addr
+            ));
+        }
+
+        let mut addrs = self.inst_name.keys().copied().collect::<Vec<_>>();
+        addrs.sort();
+        for addr in addrs {
+            if let Err(e) = Instruction::from_binary(self.mem[addr as usize / 4]) {
+                diagnostics.push(format!("{:08x}: {} ({})", addr, e, self.inst_name[&addr]));
+            }
+        }
+
+        diagnostics
+    }
+
+    /// Disassemble every instruction word and compare it with its source.
+    pub fn listing(&self) -> Vec<ListingLine> {
+        let mut addrs = self.inst_name.keys().copied().collect::<Vec<_>>();
+        addrs.sort();
+        addrs
+            .into_iter()
+            .map(|addr| {
+                let word = self.mem[addr as usize / 4];
+                let source = self.inst_name[&addr].clone();
+                let (decoded, matches) = match Instruction::from_binary(word) {
+                    Ok(inst) => {
+                        let decoded = inst.debug();
+                        let matches = self.source_matches(addr, &source, &decoded);
+                        (decoded, matches)
+                    }
+                    Err(e) => (e.to_string(), false),
+                };
+
+                ListingLine {
+                    addr,
+                    word,
+                    source,
+                    decoded,
+                    matches,
+                }
+            })
+            .collect()
+    }
+
+    /// Every word of the image as `address  word  source`, like an
+    /// assembler listing. Data words have no source, so they only show the
+    /// address and the value.
+    pub fn listing_text(&self) -> String {
+        let mut text = String::new();
+        for (i, word) in self.mem.iter().enumerate() {
+            let addr = i as u32 * 4;
+            match self.inst_name.get(&addr) {
+                Some(source) => text += &format!("{:#06x}  {:08x}  {}\n", addr, word, source),
+                None => text += &format!("{:#06x}  {:08x}\n", addr, word),
+            }
+        }
+        text
+    }
+
+    // whether the pair at `start` is the expansion of `la rd, label`,
+    // `call label` or `tail label`, registers and target included.
+    fn pseudo_matches(&self, start: u32, expected: &[String]) -> bool {
+        let word = |addr: u32| self.mem.get(addr as usize / 4).copied().unwrap_or_default();
+        let (Ok(hi), Ok(lo)) = (
+            Instruction::from_binary(word(start)),
+            Instruction::from_binary(word(start + 4)),
+        ) else {
+            return false;
+        };
+
+        // the register `auipc` writes and the second word reads, the one the
+        // second word writes, and the second word's opcode.
+        let (base, link, opcode, label) = match expected {
+            [op, rd, label] if op == "la" => match parse_reg_name(rd) {
+                Some(rd) => (rd, rd, 0x13, label),
+                None => return false,
+            },
+            [op, label] if op == "call" => (1, 1, 0x67, label),
+            [op, label] if op == "tail" => (6, 0, 0x67, label),
+            _ => return false,
+        };
+        let Some(&target) = self.symbol.get(label) else {
+            return false;
+        };
+
+        hi.binary() & 0x7f == 0x17
+            && hi.rd() == base
+            && lo.binary() & 0x707f == opcode
+            && lo.rs1() == base
+            && lo.rd() == link
+            && start.wrapping_add(hi.imm()).wrapping_add(lo.imm()) == target
+    }
+
+    fn source_matches(&self, addr: u32, source: &str, decoded: &str) -> bool {
+        let code = source.split('#').next().unwrap_or_default();
+        let code = LABEL_REGEX.replace(code, "");
+        let split = |s: &'_ str| -> Vec<String> {
+            s.split(|c: char| c == ',' || c == '(' || c == ')' || c.is_whitespace())
+                .filter(|token| !token.is_empty())
+                .map(|token| token.to_owned())
+                .collect()
+        };
+        let mut expected = split(&code);
+        let tokens = split(decoded);
+        if expected.is_empty() || tokens.is_empty() {
+            return false;
+        }
+
+        match expected[0].as_str() {
+            "csrr" => {
+                expected[0] = "csrrs".to_string();
+                expected.push("x0".to_string());
+            }
+            // pseudo instructions expand to an `auipc` and a second word,
+            // which together should reach the label.
+            "la" | "call" | "tail" => {
+                let first =
+                    addr == 0 || self.inst_line.get(&(addr - 4)) != self.inst_line.get(&addr);
+                let start = if first { addr } else { addr - 4 };
+                return tokens[0]
+                    == match expected[0].as_str() {
+                        _ if first => "auipc",
+                        "la" => "addi",
+                        _ => "jalr",
+                    }
+                    && self.pseudo_matches(start, &expected);
+            }
+            // the 20-bit field, a negative source immediate wraps into it.
+            "lui" | "auipc" => {
+                if let Some(imm) = expected.last_mut() {
+                    if let Ok(value) = parse_imm(imm) {
+                        *imm = (value & 0xfffff).to_string();
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // `lw rd, imm` and `jalr rd, rs1, imm` are printed as `imm(rs1)`.
+        if !code.contains('(') && decoded.contains('(') {
+            match expected.len() {
+                3 => expected.push("x0".to_string()),
+                4 => expected.swap(2, 3),
+                _ => {}
+            }
+        }
+
+        let normalize = |token: &String| -> String {
+            if let Some(reg) = parse_reg_name(token) {
+                format!("x{}", reg)
+            } else if let Ok(imm) = parse_imm(token) {
+                imm.to_string()
+            } else if let Some(csr) = parse_csr(token) {
+                csr.to_string()
+            } else if let Some(target) = self.symbol.get(token) {
+                target.wrapping_sub(addr).to_string()
+            } else {
+                token.clone()
+            }
+        };
+
+        expected.len() == tokens.len()
+            && expected[0] == tokens[0]
+            && expected[1..]
+                .iter()
+                .zip(tokens[1..].iter())
+                .all(|(a, b)| normalize(a) == normalize(b))
+    }
+}
+
+fn read_lines<T: BufRead>(reader: T) -> Result<Vec<String>, RvError> {
+    reader
+        .lines()
+        .map(|l| l.map(|l| l.trim().to_string()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| RvError::Io(e.to_string()))
 }
 
 fn parse_reg_name(name: &str) -> Option<u32> {
@@ -408,32 +1165,169 @@ fn parse_reg_name(name: &str) -> Option<u32> {
     }
 }
 
+// number of a csr given by name or number.
+fn parse_csr(name: &str) -> Option<u32> {
+    let csr = match name {
+        "cycle" => 0xc00,
+        "time" => 0xc01,
+        "instret" => 0xc02,
+        "cycleh" => 0xc80,
+        "timeh" => 0xc81,
+        "instreth" => 0xc82,
+        _ => parse_imm(name).ok()?,
+    };
+    (csr < 0x1000).then_some(csr)
+}
+
+// the `.equ`/`.set` constants of the source being assembled, and every word
+// its operands used so far, to catch a constant used before its definition.
+#[derive(Default)]
+struct Constants {
+    values: HashMap<String, i32>,
+    used: HashSet<String>,
+}
+
+impl Constants {
+    // replace every constant in an immediate or data `operand` with its value.
+    fn expand(&mut self, operand: &str) -> String {
+        WORD_REGEX
+            .replace_all(operand, |caps: &Captures| {
+                self.used.insert(caps[0].to_string());
+                match self.values.get(&caps[0]) {
+                    Some(value) => value.to_string(),
+                    None => caps[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+// bytes of a string literal body, with its escape sequences interpreted.
+fn unescape(data: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut iter = data.bytes();
+    while let Some(byte) = iter.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+
+        bytes.push(match iter.next() {
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'0') => 0,
+            Some(b'"') => b'"',
+            Some(b'\\') => b'\\',
+            Some(c) => return Err(format!("unknown escape sequence \\{}", c as char)),
+            None => return Err("unterminated escape sequence".to_string()),
+        });
+    }
+    Ok(bytes)
+}
+
+/// Whether `op` is written in the form `as_type`.
+fn valid_form(op: &str, opcode: u32, as_type: &AssemblyType) -> bool {
+    use AssemblyType::*;
+    match (op, opcode & 0x7f) {
+        ("la", _) => matches!(as_type, RdLabel),
+        ("call" | "tail", _) => matches!(as_type, Label),
+        (_, 0x33) => matches!(as_type, RdRs1Rs2),
+        (_, 0x13) => matches!(as_type, RdRs1Imm),
+        (_, 0x03 | 0x23) => matches!(as_type, RgImmRs1 | RdImm),
+        (_, 0x63) => matches!(as_type, Rs1Rs2Label),
+        (_, 0x6f) => matches!(as_type, RdLabel),
+        (_, 0x67) => matches!(as_type, RgImmRs1 | RdRs1Imm),
+        (_, 0x37 | 0x17) => matches!(as_type, RdImm),
+        (_, 0x73) if opcode & 0x7000 != 0 => matches!(as_type, Csr),
+        _ => matches!(as_type, OnlyOp),
+    }
+}
+
+/// Split `%hi(label)` or `%lo(label)` into the part and the label.
+// `inst` with its immediate replaced by the `part` ("hi" or "lo") of the
+// absolute address `value`.
+fn with_abs_imm(inst: u32, part: &str, value: u32) -> u32 {
+    let imm = if part == "hi" {
+        value.wrapping_add(0x800) >> 12
+    } else {
+        value & 0xfff
+    };
+    match inst & 0x7f {
+        0x37 | 0x17 => (inst & 0xfff) | (imm << 12),
+        0x23 => (inst & 0x01fff07f) | ((imm & 0x1f) << 7) | ((imm & 0xfe0) << 20),
+        _ => (inst & 0xfffff) | (imm << 20),
+    }
+}
+
+fn parse_reloc(imm: &str) -> Option<(String, String)> {
+    RELOC_REGEX.captures(imm).map(|caps| {
+        (
+            caps.name("part").unwrap().as_str().to_string(),
+            caps.name("label").unwrap().as_str().to_string(),
+        )
+    })
+}
+
 fn parse_imm(imm: &str) -> Result<u32, String> {
-    if imm.starts_with("-") {
-        let imm = &imm[1..];
-        let num = if imm.starts_with("0x") {
-            u32::from_str_radix(&imm[2..], 16)
-                .map(|x| !x + 1)
-                .map_err(|e| e.to_string())
-        } else if imm.starts_with("0b") {
-            u32::from_str_radix(&imm[2..], 2)
-                .map(|x| !x + 1)
-                .map_err(|e| e.to_string())
-        } else {
-            imm.parse::<u32>()
-                .map(|x| !x + 1)
-                .map_err(|e| e.to_string())
-        };
-        return num;
+    parse_number(imm).map(|value| value as u32)
+}
+
+// the value as written, `-1` and `0xffffffff` are told apart.
+fn parse_number(imm: &str) -> Result<i64, String> {
+    let (negative, digits) = match imm.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, imm),
+    };
+
+    let magnitude = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2)
+    } else {
+        digits.parse::<i64>()
     }
+    .map_err(|e| format!("invalid immediate {}: {}", imm, e))?;
 
-    if imm.starts_with("0x") {
-        u32::from_str_radix(&imm[2..], 16).map_err(|e| e.to_string())
-    } else if imm.starts_with("0b") {
-        u32::from_str_radix(&imm[2..], 2).map_err(|e| e.to_string())
+    // anything from `i32::MIN` to `u32::MAX` fits in 32 bits.
+    let value = if negative {
+        magnitude.wrapping_neg()
     } else {
-        imm.parse::<u32>().map_err(|e| e.to_string())
+        magnitude
+    };
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(format!("immediate out of range: {}", imm));
+    }
+
+    Ok(value)
+}
+
+// `.align 12` is a page, larger alignments would only pad the image with
+// up to gigabytes of zeros.
+const MAX_ALIGN: u32 = 12;
+
+// bytes `.align exp` aligns to.
+fn alignment(exp: u32, line: &str) -> Result<u32, String> {
+    if exp > MAX_ALIGN {
+        return Err(format!(
+            "alignment {} out of range 0-{} in {}",
+            exp as i32, MAX_ALIGN, line
+        ));
+    }
+    Ok(1 << exp)
+}
+
+// check that `imm` fits in a signed field of `bits` bits.
+fn check_imm(imm: u32, bits: u32, line: &str) -> Result<u32, String> {
+    let value = imm as i32;
+    if value < -(1 << (bits - 1)) || value >= 1 << (bits - 1) {
+        return Err(format!(
+            "immediate {} doesn't fit in {} bits in {}",
+            value, bits, line
+        ));
     }
+
+    Ok(imm)
 }
 enum AssemblyType {
     RdRs1Rs2,    // add rd, rs1, rs2
@@ -442,30 +1336,46 @@ enum AssemblyType {
     Rs1Rs2Label, // beq rs1, rs2, label
     RdLabel,     // jal rd, label
     RdImm,       // auipc rd, imm
-    OnlyOp,      // ecall and ebreak
+    Label,       // call label and tail label
+    OnlyOp,      // ecall, ebreak, fence and fence.i
+    Csr,         // csrrw rd, csr, rs1 and csrrwi rd, csr, uimm
 }
 
 lazy_static! {
     static ref LABEL_REGEX: Regex = Regex::new(r"(?P<label>\w+):").unwrap();
 
+    static ref WORD_REGEX: Regex = Regex::new(r"\w+").unwrap();
+
+    static ref RELOC_REGEX: Regex = Regex::new(r"^%(?P<part>hi|lo)\((?P<label>\w+)\)$").unwrap();
+
+    static ref EQU_REGEX: Regex = Regex::new(r"^\.(equ|set)\s+(?P<name>\w+)\s*,\s*(?P<value>\S+)").unwrap();
+
     static ref DATA_REGEX: Vec<Regex> = vec![
-        Regex::new(r#"\.(?P<type>string)\s+"(?P<data>.*)""#).unwrap(),      // .string
-        Regex::new(r"\.(?P<type>word)\s+(?P<data>[\s0-9]*)").unwrap(),      // .word
-        Regex::new(r"\.(?P<type>byte)\s+(?P<data>[\s0-9]*)").unwrap(),      // .byte
-        Regex::new(r"\.(?P<type>half)\s+(?P<data>[\s0-9]*)").unwrap(),      // .half
-        // Regex::new(r#"\.(?P<type>float)\s+(?P<data>[\s0-9]*)"#).unwrap(),   // .float
+        Regex::new(r#"\.(?P<type>string|ascii)\s+"(?P<data>.*)""#).unwrap(), // .string and .ascii
+        Regex::new(r"\.(?P<type>word)\s+(?P<data>[\s\w-]*)").unwrap(),    // .word
+        Regex::new(r"\.(?P<type>byte)\s+(?P<data>[\s\w-]*)").unwrap(),    // .byte
+        Regex::new(r"\.(?P<type>half)\s+(?P<data>[\s\w-]*)").unwrap(),    // .half
+        Regex::new(r"\.(?P<type>zero|space)\s+(?P<data>\w+)").unwrap(),     // .zero and .space
+        Regex::new(r"\.(?P<type>align)\s+(?P<data>\w+)").unwrap(),          // .align
+        Regex::new(r"\.(?P<type>float)\s+(?P<data>[\s\w.+-]*)").unwrap(),  // .float
     ];
 
+    static ref BSS_REGEX: Regex = Regex::new(r"\.(?P<type>zero|space|align)\s+(?P<data>\w+)").unwrap();
+
+    static ref COMM_REGEX: Regex = Regex::new(r"\.comm\s+(?P<label>\w+)(\s*,\s*|\s+)(?P<size>\w+)").unwrap();
+
     static ref INSTRUCTION_REGEX: Vec<(AssemblyType, Regex)> = {
         use AssemblyType::*;
         vec![
+            (Csr, Regex::new(r"\b(?P<op>csrr[wsc]?i?)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<csr>\w+)(,?\s+(?P<src>\w+))?").unwrap()),
             (RdRs1Rs2, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<rs1>([a-z][0-9]+)|zero|sp|ra|gp|tp),?\s+(?P<rs2>([a-z][0-9]+)|zero|sp|ra|gp|tp)").unwrap()),
-            (RdRs1Imm, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<rs1>([a-z][0-9]+)|zero|sp|ra|gp|tp),?\s+(?P<imm>-?(0x)?[0-9]+)").unwrap()),
-            (RgImmRs1, Regex::new(r"(?P<op>\w+)\s+(?P<rg>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<imm>-?(0x)?[0-9]+)\((?P<rs1>[a-z][0-9]+|zero|sp|ra|gp|tp)\)").unwrap()),
-            (Rs1Rs2Label, Regex::new(r"(?P<op>\w+)\s+(?P<rs1>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<rs2>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<label>[a-z][a-z_0-9]+)").unwrap()),
-            (RdLabel, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<label>[a-z][a-z_0-9]+)").unwrap()),
-            (RdImm, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<imm>-?(0x)?[0-9]+)").unwrap()),
-            (OnlyOp, Regex::new(r"(?P<op>(ecall|ebreak))").unwrap())
+            (RdRs1Imm, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<rs1>([a-z][0-9]+)|zero|sp|ra|gp|tp),?\s+(?P<imm>-?(0x[0-9a-fA-F]+|0b[01]+|[0-9]+|[A-Za-z_]\w*)|%(hi|lo)\(\w+\))").unwrap()),
+            (RgImmRs1, Regex::new(r"(?P<op>\w+)\s+(?P<rg>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<imm>-?(0x[0-9a-fA-F]+|0b[01]+|[0-9]+|[A-Za-z_]\w*)|%(hi|lo)\(\w+\))\(\s*(?P<rs1>[a-z][0-9]+|zero|sp|ra|gp|tp)\s*\)").unwrap()),
+            (Rs1Rs2Label, Regex::new(r"(?P<op>\w+)\s+(?P<rs1>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<rs2>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<label>[a-z][a-z_0-9]*)").unwrap()),
+            (RdLabel, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<label>[a-z][a-z_0-9]*)").unwrap()),
+            (RdImm, Regex::new(r"(?P<op>\w+)\s+(?P<rd>[a-z][0-9]+|zero|sp|ra|gp|tp),?\s+(?P<imm>-?(0x[0-9a-fA-F]+|0b[01]+|[0-9]+|[A-Za-z_]\w*)|%(hi|lo)\(\w+\))").unwrap()),
+            (Label, Regex::new(r"^(?P<op>call|tail)\s+(?P<label>[a-z][a-z_0-9]*)").unwrap()),
+            (OnlyOp, Regex::new(r"(?P<op>(ecall|ebreak|fence\.i|fence))").unwrap())
         ]
     };
 
@@ -477,6 +1387,10 @@ lazy_static! {
         ("mulh".to_string(), 0x02001033),
         ("mulhsu".to_string(), 0x02002033),
         ("mulhu".to_string(), 0x02003033),
+        ("div".to_string(), 0x02004033),
+        ("divu".to_string(), 0x02005033),
+        ("rem".to_string(), 0x02006033),
+        ("remu".to_string(), 0x02007033),
         ("slt".to_string(), 0x00002033),
         ("sltu".to_string(), 0x00003033),
         ("xor".to_string(), 0x00004033),
@@ -511,8 +1425,20 @@ lazy_static! {
         ("jalr".to_string(), 0x00000067),
         ("lui".to_string(), 0x00000037),
         ("auipc".to_string(), 0x00000017),
+        ("la".to_string(), 0x00000017),
+        ("call".to_string(), 0x00000017),
+        ("tail".to_string(), 0x00000017),
         ("ecall".to_string(), 0x00000073),
         ("ebreak".to_string(), 0x00100073),
+        ("csrr".to_string(), 0x00002073),
+        ("csrrw".to_string(), 0x00001073),
+        ("csrrs".to_string(), 0x00002073),
+        ("csrrc".to_string(), 0x00003073),
+        ("csrrwi".to_string(), 0x00005073),
+        ("csrrsi".to_string(), 0x00006073),
+        ("csrrci".to_string(), 0x00007073),
+        ("fence".to_string(), 0x0ff0000f),
+        ("fence.i".to_string(), 0x0000100f),
 
     ]);
 
@@ -542,24 +1468,466 @@ mod tests {
         assert_eq!(
             program.mem,
             vec![
-                0x48656c6c, 0x6f2c2077, 0x6f726c64, 0x21000000, 0x00000001, 0x00000002, 0x00000003,
-                0x00000004, 0x01020304, 0x05000000, 0x00010002, 0x00030004, 0x00050000
+                0x6c6c6548, 0x77202c6f, 0x646c726f, 0x00000021, 0x00000001, 0x00000002, 0x00000003,
+                0x00000004, 0x04030201, 0x00000005, 0x00020001, 0x00040003, 0x00000005
             ]
         );
     }
 
     #[test]
-    fn test_text_without_label() {
+    fn test_string_escape() {
         let test_str = r#"
-        .globl main
-        .text
-        main:
-        add x0, x0, x0
-        addi x0, x0, 1
-        lb x0, 1(x0)
-        sb x0, 0x21(x0)
-        auipc x0, 0
-        "#;
+        .globl end
+        .data
+        .string "a\nb"
+        .string "\"\t\\\r"
+        .ascii "\0"
+        end:
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(
+            program.mem,
+            vec![0x00620a61, 0x0d5c0922, 0x00000000, 0x00000000]
+        );
+
+        let test_str = ".globl end\n.data\n.string \"\\q\"\nend:";
+        assert!(Program::from_buffer(test_str.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_ascii() {
+        let assemble = |directive: &str| {
+            let test_str = format!(
+                ".globl end\n.data\n{} \"abc\"\n{} \"abcd\"\nend:",
+                directive, directive
+            );
+            Program::from_buffer(test_str.as_bytes()).unwrap().mem
+        };
+
+        // "abc" is only terminated by the padding, "abcd" fills its word.
+        assert_eq!(assemble(".ascii"), vec![0x00636261, 0x64636261]);
+        assert_eq!(
+            assemble(".string"),
+            vec![0x00636261, 0x64636261, 0x00000000]
+        );
+    }
+
+    #[test]
+    fn test_data_literal() {
+        let test_str = r#"
+        .globl end
+        .data
+        test_word: .word -1 0xdeadbeef
+        test_byte: .byte -1 0xff 0b101 -128
+        test_half: .half -2 0x1234 7
+        end:
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(
+            program.mem,
+            vec![0xffffffff, 0xdeadbeef, 0x8005ffff, 0x1234fffe, 0x00000007]
+        );
+
+        let test_str = r#"
+        .globl end
+        .data
+        test_byte: .byte 1 two
+        end:
+        "#;
+
+        assert!(Program::from_buffer(test_str.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_float() {
+        let test_str = r#"
+        .globl main
+        .data
+        values: .float -1.5 3.0 inf
+        small: .float 1e-3 -inf nan
+        .text
+        main:
+        add x0, x0, x0
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(&program.mem[..3], &[0xbfc00000, 0x40400000, 0x7f800000]);
+        assert_eq!(program.mem[3], 1e-3f32.to_bits());
+        assert_eq!(program.mem[4], 0xff800000);
+        assert!(f32::from_bits(program.mem[5]).is_nan());
+        assert_eq!(program.addr_of("small"), Some(12));
+        assert_eq!(program.entry(), 24);
+
+        let test_str = r#"
+        .globl main
+        .data
+        .float 1.5x
+        "#;
+        let err = Program::from_buffer(test_str.as_bytes()).err().unwrap();
+        assert!(err.to_string().starts_with("invalid float 1.5x"), "{}", err);
+    }
+
+    #[test]
+    fn test_write_file_with_endian() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        addi a0, x0, 1
+        "#;
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let path = std::env::temp_dir().join(format!("rvsim-endian-{}.bin", std::process::id()));
+
+        // addi a0, x0, 1 is 0x00100513
+        program.write_file(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), [0x13, 0x05, 0x10, 0x00]);
+        program.write_file_with_endian(&path, Endian::Big).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), [0x00, 0x10, 0x05, 0x13]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let test_str = r#"
+        .globl main
+        .data
+        msg: .string "hello"
+        .text
+        main:
+        la a0, msg
+        addi a7, x0, 93
+        ecall
+        "#;
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("rvsim-round-trip-{}.bin", std::process::id()));
+
+        for endian in [Endian::Little, Endian::Big] {
+            program.write_file_with_endian(&path, endian).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            let read = Program::from_binary(&bytes, endian).unwrap();
+            assert_eq!(read.mem, program.mem);
+            assert_eq!(read.entry(), 0);
+        }
+
+        // in little endian the string and the instructions are in memory order.
+        program.write_file(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..6], b"hello\0");
+        assert_eq!(&bytes[16..20], &[0x93, 0x08, 0xd0, 0x05]); // addi a7, x0, 93
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(Program::from_binary(&bytes[..5], Endian::Little).is_err());
+    }
+
+    #[test]
+    fn test_align_and_zero() {
+        let test_str = r#"
+        .globl aligned
+        .data
+        head: .byte 1
+        .align 4
+        aligned: .word 5
+        buf: .zero 6
+        tail:
+            .space 4
+            .word 9
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.entry(), 16);
+        assert_eq!(
+            program.mem,
+            vec![0x00000001, 0, 0, 0, 0x00000005, 0, 0, 0, 0x00000009]
+        );
+    }
+
+    #[test]
+    fn test_bss() {
+        let test_str = r#"
+        .globl buf
+        .data
+        val: .word 1
+        .bss
+        scratch: .space 6
+        .comm buf, 16
+        .text
+        main:
+        add x0, x0, x0
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.mem, vec![0x00000001, 0x00000033]);
+        assert_eq!(program.bss_size(), 24);
+        assert_eq!(program.entry(), 16);
+    }
+
+    #[test]
+    fn test_comm_spelling() {
+        // with or without spaces around the comma, as GNU as accepts.
+        for comm in [".comm buf,16", ".comm buf , 16", ".comm buf 16"] {
+            let test_str = format!(".bss\n{}\n.text\nmain:\nla a0, buf\n", comm);
+            let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+            assert_eq!(program.bss_size(), 16, "{}", comm);
+            assert_eq!(program.addr_of("buf"), Some(8), "{}", comm);
+        }
+    }
+
+    #[test]
+    fn test_hi_lo() {
+        let test_str = r#"
+        .globl main
+        .data
+        .zero 2048
+        msg: .word 7
+        .text
+        main:
+        lui a0, %hi(msg)
+        addi a0, a0, %lo(msg)
+        lui a1, %hi(msg)
+        lw a2, %lo(msg)(a1)
+        sw a2, %lo(msg)(a1)
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let msg = program.addr_of("msg").unwrap();
+        assert_eq!(msg, 0x800);
+
+        let text = &program.mem[msg as usize / 4 + 1..];
+        let decode = |word| Instruction::from_binary(word).unwrap().imm();
+        // `%lo` is negative here, so `%hi` carries one.
+        assert_eq!(text[0], 0x00001537);
+        assert_eq!(decode(text[0]).wrapping_add(decode(text[1])), msg);
+        assert_eq!(decode(text[2]).wrapping_add(decode(text[3])), msg);
+        assert_eq!(decode(text[2]).wrapping_add(decode(text[4])), msg);
+
+        let err = Program::from_buffer(".text\nlui a0, %hi(nowhere)".as_bytes()).err();
+        assert_eq!(
+            err.unwrap(),
+            RvError::Assemble {
+                line: Some(2),
+                msg: "undefined label nowhere in lui a0, %hi(nowhere)".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_buffer_and_file() {
+        let path = Path::new("tests/matrix.s");
+        let from_file = Program::from_file(path).unwrap();
+        let file = BufReader::new(File::open(path).unwrap());
+        let from_buffer = Program::from_buffer(file).unwrap();
+
+        assert_eq!(from_buffer.mem, from_file.mem);
+        assert_eq!(from_buffer.entry(), from_file.entry());
+        assert_eq!(from_buffer.inst_name, from_file.inst_name);
+        assert_eq!(from_buffer.symbol, from_file.symbol);
+    }
+
+    #[test]
+    fn test_from_buffers() {
+        let main = r#"
+        .equ N, 2
+        .globl main
+        .text
+        main:
+        la a0, value
+        call add_n
+        "#;
+        let lib = r#"
+        .equ N, 3
+        .data
+        value: .word 7
+        .text
+        add_n:
+        addi a0, a0, N
+        jalr x0, ra, 0
+        "#;
+
+        let sources = vec![
+            ("main.s".to_string(), main.as_bytes()),
+            ("lib.s".to_string(), lib.as_bytes()),
+        ];
+        let program = Program::from_buffers(sources).unwrap();
+        assert_eq!(program.entry(), 0);
+        assert_eq!(program.addr_of("value"), Some(0x10));
+        assert_eq!(program.addr_of("add_n"), Some(0x14));
+        // la a0, value; call add_n, each an auipc pair
+        assert_eq!(
+            program.mem[..4],
+            [0x00000517, 0x01050513, 0x00000097, 0x00c080e7]
+        );
+        // `N` is 3 in lib.s
+        assert_eq!(program.mem[5], 0x00350513);
+
+        // labels are shared, so they can't be defined twice.
+        let sources = vec![
+            (
+                "main.s".to_string(),
+                ".globl main\n.text\nmain: add x0, x0, x0".as_bytes(),
+            ),
+            (
+                "lib.s".to_string(),
+                ".text\nmain: add x0, x0, x0".as_bytes(),
+            ),
+        ];
+        assert_eq!(
+            Program::from_buffers(sources).err(),
+            Some(RvError::Assemble {
+                line: Some(2),
+                msg: "lib.s: duplicate label: main".to_string()
+            })
+        );
+
+        // a source doesn't continue the section the previous one ended in.
+        let sources = vec![
+            (
+                "main.s".to_string(),
+                ".globl main\n.text\nmain: add x0, x0, x0".as_bytes(),
+            ),
+            ("lib.s".to_string(), "add x0, x0, x0".as_bytes()),
+        ];
+        assert_eq!(Program::from_buffers(sources).unwrap().mem.len(), 1);
+    }
+
+    #[test]
+    fn test_from_buffers_lines() {
+        // both sources have an instruction on line 3.
+        let sources = vec![
+            (
+                "a.s".to_string(),
+                ".globl main\n.text\nmain: add x0, x0, x0".as_bytes(),
+            ),
+            ("b.s".to_string(), ".text\n\nsub x0, x0, x0".as_bytes()),
+        ];
+        let program = Program::from_buffers(sources).unwrap();
+        assert_eq!(program.line_to_address(0, 3), Some(0));
+        assert_eq!(program.line_to_address(1, 3), Some(4));
+        assert_eq!(program.address_to_line(4), Some((1, 3)));
+        assert_eq!(program.file_name(1), Some("b.s"));
+        assert_eq!(program.file_name(2), None);
+
+        // an undefined label is reported in the source which uses it.
+        let sources = vec![
+            (
+                "a.s".to_string(),
+                ".globl main\n.text\nmain: add x0, x0, x0".as_bytes(),
+            ),
+            ("b.s".to_string(), ".text\n\njal x0, nowhere".as_bytes()),
+        ];
+        assert_eq!(
+            Program::from_buffers(sources).err(),
+            Some(RvError::Assemble {
+                line: Some(3),
+                msg: "b.s: undefined label nowhere in jal x0, nowhere".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_section() {
+        let test_str = r#"
+        .globl main
+        .section .rodata
+        table: .word 7 8
+        .section .text
+        main:
+        add x0, x0, x0
+        .section .data
+        test_byte: .byte 1 2 3 4
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(
+            program.mem,
+            vec![0x00000007, 0x00000008, 0x00000033, 0x04030201]
+        );
+        assert_eq!(program.entry(), 8);
+        assert_eq!(
+            program.inst_name,
+            HashMap::from([(8, "add x0, x0, x0".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_interleaved_sections() {
+        let test_str = r#"
+        .globl main
+        .data
+        first: .word 1 2
+        .text
+        main:
+        la a0, second
+        lw a1, 0(a0)
+        .data
+        second: .half 3 4
+        third: .byte 5
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.addr_of("first"), Some(0));
+        assert_eq!(program.addr_of("main"), Some(8));
+        assert_eq!(program.addr_of("second"), Some(20));
+        assert_eq!(program.addr_of("third"), Some(24));
+        assert_eq!(program.entry(), 8);
+        assert_eq!(program.mem.len(), 7);
+        assert_eq!(program.mem[5], 0x00040003);
+        assert_eq!(program.mem[6], 0x00000005);
+
+        let inst = Instruction::from_binary(program.mem[2]).unwrap();
+        assert_eq!(inst.debug(), "auipc x10, 0x0");
+        let inst = Instruction::from_binary(program.mem[3]).unwrap();
+        assert_eq!(inst.debug(), "addi x10, x10, 12");
+    }
+
+    #[test]
+    fn test_validate() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        addi a0, x0, 17
+        ecall
+        "#;
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert!(program.validate().is_empty());
+
+        let test_str = r#"
+        .globl msg
+        .data
+        msg: .word 1
+        .text
+        main:
+        ecall
+        "#;
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(
+            program.validate(),
+            vec!["program entry 00000000 is not an instruction".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_text_without_label() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        add x0, x0, x0
+        addi x0, x0, 1
+        lb x0, 1(x0)
+        sb x0, 0x21(x0)
+        auipc x0, 0
+        "#;
 
         let program = Program::from_buffer(test_str.as_bytes()).unwrap();
 
@@ -579,6 +1947,635 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mem_operand_whitespace() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        lw x1, 8(x2)
+        lw x1, 8( x2 )
+        sw x1, -4(  sp)
+        sw x1, -4(sp )
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.mem[0], program.mem[1]);
+        assert_eq!(program.mem[2], program.mem[3]);
+        assert_eq!(
+            program.mem,
+            vec![0x00812083, 0x00812083, 0xfe112e23, 0xfe112e23]
+        );
+    }
+
+    #[test]
+    fn test_constant() {
+        let with_constant = r#"
+        .equ SIZE, 0x10
+        .set NEG, -4
+        .globl main
+        .data
+        buf: .word SIZE NEG
+        .text
+        main:
+        addi x1, x0, SIZE
+        lw x2, NEG(sp)
+        "#;
+        let with_literal = r#"
+        .globl main
+        .data
+        buf: .word 0x10 -4
+        .text
+        main:
+        addi x1, x0, 0x10
+        lw x2, -4(sp)
+        "#;
+
+        let program = Program::from_buffer(with_constant.as_bytes()).unwrap();
+        let expected = Program::from_buffer(with_literal.as_bytes()).unwrap();
+
+        assert_eq!(program.mem, expected.mem);
+        assert_eq!(program.inst_name[&8], "addi x1, x0, SIZE");
+
+        let redefined = r#"
+        .equ SIZE, 1
+        .equ SIZE, 2
+        "#;
+        assert!(Program::from_buffer(redefined.as_bytes()).is_err());
+
+        let forward = r#"
+        .globl main
+        .text
+        main:
+        addi x1, x0, SIZE
+        .equ SIZE, 1
+        "#;
+        assert!(Program::from_buffer(forward.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_constant_operands() {
+        // constants only replace immediates and data, not registers, labels,
+        // mnemonics, strings or comments.
+        let with_constant = r#"
+        # N is the count
+        .equ a0, 5
+        .equ loop, 1
+        .equ addi, 2
+        .equ N, 3
+        .globl main
+        .data
+        msg: .string "N"
+        .text
+        main:
+        addi a0, a0, loop
+        loop:
+        addi a1, a1, N
+        bne a0, a1, loop
+        "#;
+        let with_literal = r#"
+        .globl main
+        .data
+        msg: .string "N"
+        .text
+        main:
+        addi a0, a0, 1
+        loop:
+        addi a1, a1, 3
+        bne a0, a1, loop
+        "#;
+
+        let program = Program::from_buffer(with_constant.as_bytes()).unwrap();
+        let expected = Program::from_buffer(with_literal.as_bytes()).unwrap();
+        assert_eq!(program.mem, expected.mem);
+        assert_eq!(program.mem[0], u32::from_le_bytes(*b"N\0\0\0"));
+        assert_eq!(program.addr_of("loop"), Some(8));
+    }
+
+    #[test]
+    fn test_la() {
+        let test_str = r#"
+        .globl main
+        .data
+        msg: .word 42
+        .text
+        main:
+        la a0, msg
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        // auipc a0, 0; addi a0, a0, -4
+        assert_eq!(program.mem, vec![0x0000002a, 0x00000517, 0xffc50513]);
+        assert_eq!(program.text_size(), 8);
+        assert_eq!(program.inst_name[&4], "la a0, msg");
+        assert_eq!(program.inst_name[&8], "la a0, msg");
+    }
+
+    #[test]
+    fn test_call_and_tail() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        call func
+        tail func
+        add x0, x0, x0
+        func:
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(
+            program.mem,
+            vec![
+                0x00000097, // auipc ra, 0
+                0x014080e7, // jalr ra, 20(ra)
+                0x00000317, // auipc t1, 0
+                0x00c30067, // jalr x0, 12(t1)
+                0x00000033,
+            ]
+        );
+        assert_eq!(program.text_size(), 20);
+    }
+
+    #[test]
+    fn test_line_mapping() {
+        let test_str = ".globl main
+.data
+msg: .word 1
+.text
+main: add x0, x0, x0
+
+la a0, msg
+ecall
+call main";
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.line_to_address(0, 5), Some(4));
+        assert_eq!(program.line_to_address(0, 6), None);
+        assert_eq!(program.line_to_address(0, 7), Some(8));
+        assert_eq!(program.line_to_address(0, 8), Some(16));
+        assert_eq!(program.line_to_address(1, 5), None);
+        assert_eq!(program.address_to_line(4), Some((0, 5)));
+        assert_eq!(program.address_to_line(12), Some((0, 7)));
+        assert_eq!(program.address_to_line(0), None);
+        // both words of `la` and `call` map back to their line.
+        assert_eq!(program.address_to_line(24), Some((0, 9)));
+        assert_eq!(program.file_name(0), None);
+    }
+
+    #[test]
+    fn test_upper_immediate() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        lui x1, 0x12345
+        auipc x2, 1
+        lui x3, 1048575
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.mem, vec![0x123450b7, 0x00001117, 0xfffff1b7]);
+
+        // loads and stores without a base register still take an I or S
+        // immediate.
+        let test_str = ".globl main\n.text\nmain: lw a1, 8\nsw a1, 36";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.mem, vec![0x00802583, 0x02b02223]);
+    }
+
+    #[test]
+    fn test_listing() {
+        let test_str = r#"
+        .globl main
+        .data
+        msg: .word 1
+        .text
+        main:
+        la a0, msg
+        lw a1, 0(a0)
+        sw a1, 4
+        jalr x0, ra, 0 # ret
+        loop: bne a1, zero, loop
+        lui t0, 0x12345
+        ebreak
+        "#;
+
+        let mut program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let listing = program.listing();
+
+        assert_eq!(listing.len(), 8);
+        assert!(listing.iter().all(|line| line.matches));
+        assert_eq!(listing[3].source, "sw a1, 4");
+        assert_eq!(listing[3].decoded, "sw x11, 4(x0)");
+
+        // `sw a1, 8` is not what the source says.
+        program.mem[4] = 0x00b02423;
+        assert!(!program.listing()[3].matches);
+    }
+
+    #[test]
+    fn test_listing_text() {
+        let test_str = r#"
+        .globl main
+        .data
+        one: .word 1
+        .text
+        main:
+        addi x1, x0, 1
+        loop: bne x1, x0, loop
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(
+            program.listing_text(),
+            "0x0000  00000001\n\
+             0x0004  00100093  addi x1, x0, 1\n\
+             0x0008  00009063  loop: bne x1, x0, loop\n"
+        );
+    }
+
+    #[test]
+    fn test_branch_range() {
+        let far = |count: usize| {
+            format!(
+                ".globl main\n.text\nmain: beq x0, x0, far\n{}far: ecall",
+                "addi x0, x0, 0\n".repeat(count)
+            )
+        };
+
+        // 4 + 4 * 1022 = 4092 bytes is the farthest forward branch.
+        assert!(Program::from_buffer(far(1022).as_bytes()).is_ok());
+        assert_eq!(
+            Program::from_buffer(far(1023).as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("branch target out of range: label 'far' is 4096 bytes away".to_string())
+        );
+    }
+
+    #[test]
+    fn test_jalr() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        jalr ra, t0, 4
+        jalr x0, -4(ra)
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.mem, vec![0x004280e7, 0xffc08067]);
+    }
+
+    #[test]
+    fn test_assemble_str() {
+        let test_str = include_str!("../tests/dot.s");
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(Program::assemble_str(test_str).unwrap(), program.mem);
+
+        assert!(Program::assemble_str(".globl main\n.text\nmain: nop").is_err());
+    }
+
+    #[test]
+    fn test_fence() {
+        let test_str = ".globl main\n.text\nmain: fence\nfence.i";
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.mem, vec![0x0ff0000f, 0x0000100f]);
+        assert!(program.listing().iter().all(|line| line.matches));
+    }
+
+    #[test]
+    fn test_csr() {
+        let test_str = ".globl main
+.text
+main: csrr a1, cycle
+csrrw a2, 0x340, t0
+csrrci x0, 0x340, 3";
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        assert_eq!(program.mem, vec![0xc00025f3, 0x34029673, 0x3401f073]);
+        assert!(program.listing().iter().all(|line| line.matches));
+    }
+
+    #[test]
+    fn test_listing_pseudo() {
+        let test_str = r#"
+        .globl main
+        .data
+        msg: .word 1
+        .text
+        main:
+        la a0, msg
+        call f
+        tail f
+        f: ebreak
+        "#;
+
+        let mut program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert!(program.listing().iter().all(|line| line.matches));
+
+        // `la a0, msg + 4`, `la a1, msg`, `call` linking t0 and `tail`
+        // through t2 have the right mnemonics but not the source's operands.
+        let cases = [
+            (2, 0x00450513),
+            (1, 0x00000597),
+            (4, 0x010082e7),
+            (5, 0x00000397),
+        ];
+        for (index, word) in cases {
+            let old = std::mem::replace(&mut program.mem[index], word);
+            // both lines of the pair are off.
+            let pair = (index - 1) / 2 * 2;
+            let listing = program.listing();
+            assert!(!listing[pair].matches, "{:08x}", word);
+            assert!(!listing[pair + 1].matches, "{:08x}", word);
+            assert_eq!(listing.iter().filter(|line| !line.matches).count(), 2);
+            program.mem[index] = old;
+        }
+    }
+
+    #[test]
+    fn test_align_range() {
+        let assemble = |src: &str| {
+            Program::from_buffer(src.as_bytes())
+                .map(|program| program.mem.len())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            assemble(".globl main\n.data\n.byte 1\n.align 12\n.text\nmain: ecall"),
+            Ok(1025)
+        );
+        assert_eq!(
+            assemble(".globl main\n.data\n.align 31\n.text\nmain: ecall"),
+            Err("alignment 31 out of range 0-12 in .align 31".to_string())
+        );
+        assert_eq!(
+            assemble(".globl main\n.bss\n.align 13\n.text\nmain: ecall"),
+            Err("alignment 13 out of range 0-12 in .align 13".to_string())
+        );
+    }
+
+    #[test]
+    fn test_data_range() {
+        let assemble = |data: &str| {
+            Program::from_buffer(
+                format!(".globl main\n.data\n{}\n.text\nmain: ecall", data).as_bytes(),
+            )
+            .map(|program| program.mem[0])
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(assemble(".byte -128 255"), Ok(0x0000ff80));
+        assert_eq!(assemble(".half -32768 65535"), Ok(0xffff8000));
+        assert_eq!(
+            assemble(".byte 300"),
+            Err("value out of range: 300 in .byte 300".to_string())
+        );
+        assert!(assemble(".byte -129").is_err());
+        assert_eq!(
+            assemble(".half 70000"),
+            Err("value out of range: 70000 in .half 70000".to_string())
+        );
+        assert!(assemble(".half -32769").is_err());
+    }
+
+    #[test]
+    fn test_csr_uimm() {
+        let assemble = |inst: &str| {
+            Program::from_buffer(format!(".globl main\n.text\nmain: {}", inst).as_bytes())
+                .map(|program| program.mem)
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(assemble("csrrwi x0, 0x340, 31"), Ok(vec![0x340fd073]));
+        assert_eq!(
+            assemble("csrrwi x0, 0x340, 40"),
+            Err("immediate 40 out of range 0-31 in main: csrrwi x0, 0x340, 40".to_string())
+        );
+        assert!(assemble("csrrsi x0, 0x340, -1").is_err());
+    }
+
+    #[test]
+    fn test_parse_imm() {
+        assert_eq!(parse_imm("-0x7ff"), Ok(0xfffff801));
+        assert_eq!(parse_imm("-2048"), Ok(0xfffff800));
+        assert_eq!(parse_imm("-0b11"), Ok(0xfffffffd));
+        assert_eq!(parse_imm("-0"), Ok(0));
+        assert_eq!(parse_imm("-0x80000000"), Ok(0x80000000));
+        assert_eq!(parse_imm("0xffffffff"), Ok(0xffffffff));
+        assert_eq!(
+            parse_imm("-0x100000000"),
+            Err("immediate out of range: -0x100000000".to_string())
+        );
+        assert!(parse_imm("0x100000000").is_err());
+        assert!(parse_imm("-0x80000001").is_err());
+        assert!(parse_imm("twelve").is_err());
+
+        let assemble = |inst: &str| {
+            Program::from_buffer(format!(".globl main\n.text\nmain: {}", inst).as_bytes())
+                .map(|program| program.mem)
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(assemble("addi x1, x0, -2048"), Ok(vec![0x80000093]));
+        assert_eq!(assemble("addi x1, x0, -0x7ff"), Ok(vec![0x80100093]));
+        assert_eq!(
+            assemble("addi x1, x0, 2048"),
+            Err("immediate 2048 doesn't fit in 12 bits in main: addi x1, x0, 2048".to_string())
+        );
+        assert!(assemble("lw x1, -2049(x2)").is_err());
+        assert!(assemble("lui x1, 0x100000").is_err());
+        assert_eq!(
+            assemble("lui x1, 4294963200"),
+            Err(
+                "immediate 4294963200 doesn't fit in 20 bits in main: lui x1, 4294963200"
+                    .to_string()
+            )
+        );
+        assert_eq!(assemble("lui x1, -524288"), Ok(vec![0x800000b7]));
+        assert!(assemble("auipc x1, -524289").is_err());
+    }
+
+    #[test]
+    fn test_shift_amount() {
+        let assemble = |inst: &str| {
+            Program::from_buffer(format!(".globl main\n.text\nmain: {}", inst).as_bytes())
+                .map(|program| program.mem)
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(assemble("slli x1, x2, 31"), Ok(vec![0x01f11093]));
+        assert_eq!(assemble("srli x1, x2, 31"), Ok(vec![0x01f15093]));
+        assert_eq!(assemble("srai x1, x2, 31"), Ok(vec![0x41f15093]));
+        assert_eq!(
+            assemble("srli x1, x2, 32"),
+            Err("shift amount 32 out of range 0-31 in main: srli x1, x2, 32".to_string())
+        );
+        assert!(assemble("slli x1, x2, 1056").is_err());
+        assert!(assemble("srai x1, x2, -1").is_err());
+    }
+
+    #[test]
+    fn test_globals() {
+        let test_str = ".globl start
+.globl begin
+.text
+addi x1, x0, 1
+begin: ecall";
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.entry(), 4);
+
+        let test_str = ".globl helper, main
+.text
+helper: addi x1, x0, 1
+main: ecall";
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.entry(), 4);
+
+        let test_str = ".globl start, begin\n.text\necall";
+        assert_eq!(
+            Program::from_buffer(test_str.as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("program entry not found, undefined globals: start, begin".to_string())
+        );
+    }
+
+    #[test]
+    fn test_entry_without_globl() {
+        let test_str = ".text\nhelper: addi x1, x0, 1\nmain: ecall";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.entry(), 4);
+
+        // `_start` is preferred to `main`.
+        let test_str = ".text\nmain: addi x1, x0, 1\n_start: ecall";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.entry(), 4);
+
+        let test_str = ".text\nbegin: ecall";
+        assert_eq!(
+            Program::from_buffer(test_str.as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("program entry not found".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unrecognized_instruction() {
+        let test_str = ".globl main
+.text
+main: addi x1, x0, 1
+addi x2, x0, 2
+ecal # typo";
+
+        assert_eq!(
+            Program::from_buffer(test_str.as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("line 5: unrecognized instruction: ecal".to_string())
+        );
+
+        let test_str = ".globl main\n.text\nmain: nop\n";
+        assert_eq!(
+            Program::from_buffer(test_str.as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("line 3: unrecognized instruction: nop".to_string())
+        );
+
+        // the line is kept apart from the message.
+        let err = Program::from_buffer(
+            ".globl main\n.text\nmain:\nbeq x0, x0, nowhere\naddi x1, x0, 4096\n".as_bytes(),
+        )
+        .err();
+        assert!(matches!(err, Some(RvError::Assemble { line: Some(5), .. })));
+        // as is an undefined label's, found once every line is assembled.
+        let err = Program::from_buffer(".text\nbeq x0, x0, nowhere\n".as_bytes()).err();
+        assert!(matches!(err, Some(RvError::Assemble { line: Some(2), .. })));
+    }
+
+    #[test]
+    fn test_invalid_operands() {
+        // `x2` would be taken as the label of a `jal` like form.
+        let test_str = ".globl main\n.text\nmain: addi x1, x2\n";
+        assert_eq!(
+            Program::from_buffer(test_str.as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("line 3: invalid operands for addi: addi x1, x2".to_string())
+        );
+
+        let test_str = ".globl main\n.text\nmain: jal ra, 8\n";
+        assert_eq!(
+            Program::from_buffer(test_str.as_bytes())
+                .err()
+                .map(|e| e.to_string()),
+            Some("line 3: invalid operands for jal: jal ra, 8".to_string())
+        );
+    }
+
+    #[test]
+    fn test_short_label() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        l:
+        beq x0, x0, l
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        // beq x0, x0, 0
+        assert_eq!(program.mem, vec![0x00000063]);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let test_str = ".globl main
+.text
+helper: addi x1, x0, 1
+main:
+loop: ecall";
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.addr_of("helper"), Some(0));
+        assert_eq!(program.addr_of("main"), Some(4));
+        assert_eq!(program.addr_of("loop"), Some(4));
+        assert_eq!(program.addr_of("missing"), None);
+        assert_eq!(program.symbols().len(), 3);
+    }
+
+    #[test]
+    fn test_labels() {
+        let test_str = r#"
+        .globl main
+        .text
+        main:
+        loop:
+        addi a0, a0, -1
+        bne a0, zero, loop
+        "#;
+
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let labels = program.labels();
+
+        assert_eq!(labels.get(&0), Some(&"loop".to_string()));
+        let inst = Instruction::from_binary(program.mem[1]).unwrap();
+        assert_eq!(inst.debug_pretty(4, &labels), "bne a0, zero, loop");
+    }
+
     #[test]
     fn test_text_with_label() {
         let test_str = r#"
@@ -638,8 +2635,8 @@ mod tests {
         assert_eq!(
             program.mem,
             vec![
-                0x48656c6c, 0x6f2c2077, 0x6f726c64, 0x21000000, 0x00000001, 0x00000002, 0x00000003,
-                0x00000004, 0x01020304, 0x05000000, 0x00010002, 0x00030004, 0x00050000, 0x00000033,
+                0x6c6c6548, 0x77202c6f, 0x646c726f, 0x00000021, 0x00000001, 0x00000002, 0x00000003,
+                0x00000004, 0x04030201, 0x00000005, 0x00020001, 0x00040003, 0x00000005, 0x00000033,
                 0x00000033, 0x00000033, 0xfe000ee3, 0x0040006f
             ]
         );
@@ -653,5 +2650,7 @@ mod tests {
                 (17 * 4, "jal x0, end".to_string()),
             ])
         );
+        assert_eq!(program.text_size(), 5 * 4);
+        assert_eq!(program.data_size(), 13 * 4);
     }
 }
@@ -1,12 +1,14 @@
 use super::{
+    alu::{alu, AluType},
     assembler::Program,
-    instruction::{AluType, Instruction, MemType, WBType},
+    error::RvError,
+    instruction::{Instruction, MemType, WBType},
 };
-use nu_ansi_term::Color::Blue;
+use nu_ansi_term::Color::{Blue, Yellow};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::{self, Display},
-    ops::Index,
+    ops::{Index, Range},
 };
 
 #[derive(Default)]
@@ -17,13 +19,44 @@ pub struct CpuState {
     mem_wb: TempState,
     regs: Register,
     mem: Memory,
+    imem: Memory,
+    split_memory: bool,
+    text: HashSet<u32>,
+    // what `load` left in the memories and the entry, for `reset`.
+    image: Vec<u32>,
+    text_image: Vec<u32>,
+    loaded: Range<u32>,
+    entry: u32,
     pc: u32,
     npc: u32,
     inst_name: HashMap<u32, String>,
+    // address to label, to show where jumps go.
+    labels: BTreeMap<u32, String>,
     stall: bool,
     cycle: u32,
     data_hazard: u32,
     control_hazard: u32,
+    retired: u32,
+    stack_low: Option<u32>,
+    fetch_delay: u32,
+    fetch_wait: u32,
+    fetch_stall: u32,
+    load_delay: u32,
+    load_wait: u32,
+    // cycles left for a load or store which missed the cache, the whole
+    // pipeline waits behind it.
+    mem_wait: u32,
+    cache_stall: u32,
+    predictor: Option<BranchPredictor>,
+    branch_mispredicts: u32,
+    compressed: bool,
+    trap_on_overflow: bool,
+    trap_on_div_zero: bool,
+    cache: Option<Cache>,
+    mmio: Vec<Mmio>,
+    csrs: HashMap<u32, u32>,
+    timeline: Vec<InstTimeline>,
+    compact_display: bool,
     exit: bool,
 }
 
@@ -36,46 +69,237 @@ struct TempState {
     imm_b: u32,
     imm_src: u32,
     cond: bool,
+    pred_taken: bool,
     alu_out: u32,
     mem_out: u32,
     write_out: u32,
+    // false for the bubbles put in by stalls and flushes, which are told
+    // apart from a `nop` written in the program by this and not by `ir`.
+    valid: bool,
+    // index of the instruction in `CpuState::timeline`, none for bubbles.
+    id: Option<usize>,
 }
 
+const STACK_TOP: u32 = 0x7ffc;
+
+const MEMORY_SIZE: u32 = 32 * 1024;
+
+const MAX_MEMORY_SIZE: u32 = 64 * 1024 * 1024;
+
+const MAX_PREDICTOR_BITS: u32 = 20;
+
+const MAX_CACHE_LINES: u32 = 1 << 16;
+
 struct Memory {
-    data: [u32; 1024 * 8], // 32KB
+    data: Vec<u32>,
 }
 
 struct Register {
     regs: [u32; 32],
 }
 
+// 2-bit saturating counters indexed by the low bits of the branch pc.
+struct BranchPredictor {
+    counters: Vec<u8>,
+    #[cfg(test)]
+    forced: HashMap<u32, bool>,
+}
+
+// device registers, loads and stores in `range` call these instead of
+// reaching the memory.
+struct Mmio {
+    range: Range<u32>,
+    read: Box<dyn FnMut(u32) -> u32>,
+    write: Box<dyn FnMut(u32, u32)>,
+}
+
+/// Shape of the direct-mapped data cache, `lines` and `line_size` (in
+/// bytes) are powers of two.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CacheConfig {
+    pub lines: u32,
+    pub line_size: u32,
+    /// Extra cycles a load which misses keeps its user waiting.
+    pub miss_penalty: u32,
+}
+
+// direct-mapped and write-allocate, it only tracks tags since the data is
+// always read from the memory.
+struct Cache {
+    config: CacheConfig,
+    tags: Vec<Option<u32>>,
+    hits: u32,
+    misses: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum RunState {
     Running,
     Exit(u32),
     Break,
+    /// An `ecall` with a service number the simulator doesn't know.
+    EcallUnknown {
+        a7: u32,
+        a0: u32,
+    },
 }
 
-impl CpuState {
-    fn if_cycle(&mut self) -> Result<(), String> {
-        if self.ex_mem.cond {
-            self.npc = self.ex_mem.alu_out;
+/// Pipeline register an in-flight instruction is currently latched in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    IdEx,
+    ExMem,
+    MemWb,
+}
+
+/// In-flight instruction which will write a register.
+pub struct Producer {
+    pub stage: Stage,
+    pub pc: u32,
+    pub inst: Instruction,
+}
+
+/// What a single `step` did, as yielded by [`CpuState::cycles`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CycleTrace {
+    pub cycle: u32,
+    /// Address of the next instruction to complete after the cycle.
+    pub pc: u32,
+    pub retired: u32,
+    pub state: RunState,
+}
+
+/// Iterator stepping a [`CpuState`] until the program exits or fails.
+pub struct Cycles<'a> {
+    cpu: &'a mut CpuState,
+    done: bool,
+}
+
+impl Iterator for Cycles<'_> {
+    type Item = Result<CycleTrace, RvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let state = match self.cpu.step() {
+            Ok(state) => state,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        self.done = matches!(state, RunState::Exit(_));
+        Some(Ok(CycleTrace {
+            cycle: self.cpu.cycle,
+            pc: self.cpu.pc,
+            retired: self.cpu.retired,
+            state,
+        }))
+    }
+}
+
+/// When an instruction went through the pipeline, as listed by
+/// [`CpuState::timeline`]. The cycles are numbered like `CpuState::cycle`.
+#[derive(Clone)]
+pub struct InstTimeline {
+    pub pc: u32,
+    pub inst: Instruction,
+    /// Cycle it was decoded and issued in ID.
+    pub issue: u32,
+    pub execute: Option<u32>,
+    pub write_back: Option<u32>,
+}
+
+/// Outcome of [`run`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct RunSummary {
+    pub exit_code: u32,
+    pub cycles: u32,
+    pub instructions: u32,
+    /// Registers at the exit, `x0` to `x31`.
+    pub regs: [u32; 32],
+}
+
+/// Assemble `src` and run it on a default cpu until it exits, for tests and
+/// quick checks. `ebreak` is ignored and an unknown `ecall` is an error.
+pub fn run(src: &str) -> Result<RunSummary, RvError> {
+    let program = Program::from_buffer(src.as_bytes())?;
+    let mut cpu = CpuState::default();
+    cpu.load(&program)?;
+    loop {
+        match cpu.step()? {
+            RunState::Exit(exit_code) => {
+                return Ok(RunSummary {
+                    exit_code,
+                    cycles: cpu.cycle,
+                    instructions: cpu.retired,
+                    regs: cpu.regs.regs,
+                })
+            }
+            RunState::EcallUnknown { a7, a0 } => return Err(RvError::UnknownEcall { a7, a0 }),
+            RunState::Running | RunState::Break => {}
         }
+    }
+}
 
-        if self.id_ex.ir.is_jump() || self.exit {
+impl CpuState {
+    fn if_cycle(&mut self) -> Result<(), RvError> {
+        // without a predictor, fetch waits until the jump is resolved.
+        if (self.predictor.is_none() && self.id_ex.ir.is_jump()) || self.exit {
             self.if_id.ir = Instruction::nop();
+            self.if_id.valid = false;
             return Ok(());
         } else if !self.stall {
-            self.if_id.ir = Instruction::from_binary(self.mem.load(self.npc)).unwrap();
-        }
-
-        if self.if_id.ir.is_ecall() {
-            self.exit = true;
+            // fetch latency, every instruction spends `fetch_delay` extra
+            // cycles in IF before it can be issued.
+            if self.fetch_wait < self.fetch_delay {
+                self.fetch_wait += 1;
+                self.fetch_stall += 1;
+                self.if_id.ir = Instruction::nop();
+                self.if_id.valid = false;
+                return Ok(());
+            }
+            self.fetch_wait = 0;
+            // like a real core this traps instead of fetching the enclosing
+            // word, instructions are 2 byte aligned only with compressed ones.
+            if !self.npc.is_multiple_of(self.inst_align()) {
+                return Err(RvError::MisalignedFetch { target: self.npc });
+            }
+            let fetched = self.fetch(self.npc);
+            self.if_id.ir = match fetched {
+                Ok(inst) => inst,
+                // past a predicted jump this may be the wrong path, so wait
+                // for the jump to resolve and only fail if fetch comes back.
+                Err(_) if self.id_ex.ir.is_jump() => {
+                    self.if_id.ir = Instruction::nop();
+                    self.if_id.valid = false;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            self.if_id.valid = true;
         }
 
         if !self.stall {
-            self.if_id.npc = self.npc + 4;
+            self.if_id.npc = self.npc + self.if_id.ir.size();
             self.if_id.pc = self.npc;
-            self.npc = self.if_id.npc;
+            // a misaligned target is never predicted, so that only a jump
+            // which is really taken can fetch from it and trap.
+            let target = self.if_id.pc.wrapping_add(self.if_id.ir.imm());
+            self.if_id.pred_taken = match &self.predictor {
+                Some(predictor) => {
+                    predictor.predict(&self.if_id.ir, self.if_id.pc)
+                        && target.is_multiple_of(self.inst_align())
+                }
+                None => false,
+            };
+            self.npc = if self.if_id.pred_taken {
+                target
+            } else {
+                self.if_id.npc
+            };
         }
 
         Ok(())
@@ -84,17 +308,58 @@ impl CpuState {
     fn id_cycle(&mut self) {
         self.stall = false;
 
-        // data hazard
-        if self.id_ex.ir.is_load()
+        // the jump in EX has been resolved, redirect fetch and flush the
+        // wrong path instruction if it didn't go where it was predicted.
+        if self.ex_mem.ir.is_jump() {
+            if let Some(predictor) = &mut self.predictor {
+                predictor.update(&self.ex_mem.ir, self.ex_mem.pc, self.ex_mem.cond);
+            }
+
+            if self.ex_mem.cond != self.ex_mem.pred_taken {
+                self.npc = if self.ex_mem.cond {
+                    self.ex_mem.alu_out
+                } else {
+                    self.ex_mem.npc
+                };
+                if self.predictor.is_some() {
+                    self.branch_mispredicts += 1;
+                }
+
+                self.load_wait = 0;
+                self.if_id.ir = Instruction::nop();
+                self.if_id.valid = false;
+                self.id_ex.ir = Instruction::nop();
+                self.id_ex.valid = false;
+                self.id_ex.pc = self.if_id.pc;
+                self.id_ex.npc = self.if_id.npc;
+                self.id_ex.pred_taken = false;
+                self.id_ex.id = None;
+                self.id_ex.imm_a = 0;
+                self.id_ex.imm_b = 0;
+                self.id_ex.imm_src = 0;
+                return;
+            }
+        }
+
+        // data hazard, a slow load keeps its user waiting `load_delay`
+        // more cycles after the usual one.
+        if self.load_wait > 0 {
+            self.load_wait -= 1;
+            self.stall = true;
+            self.data_hazard += 1;
+        } else if self.id_ex.ir.is_load()
             && (self.id_ex.ir.rd() == self.if_id.ir.rs1()
                 || self.id_ex.ir.rd() == self.if_id.ir.rs2())
         {
+            self.load_wait = self.load_delay;
             self.stall = true;
             self.data_hazard += 1;
         }
 
         if self.stall {
             self.id_ex.ir = Instruction::nop();
+            self.id_ex.valid = false;
+            self.id_ex.id = None;
             self.id_ex.pc = self.if_id.pc;
             self.id_ex.npc = self.if_id.npc;
             self.id_ex.imm_a = 0;
@@ -105,25 +370,51 @@ impl CpuState {
 
         self.id_ex.pc = self.if_id.pc;
         self.id_ex.npc = self.if_id.npc;
+        self.id_ex.pred_taken = self.if_id.pred_taken;
         self.id_ex.ir = self.if_id.ir.clone();
         self.id_ex.imm_a = self.regs[self.if_id.ir.rs1()];
         self.id_ex.imm_b = self.regs[self.if_id.ir.rs2()];
         self.id_ex.imm_src = self.if_id.ir.imm();
+        self.id_ex.valid = self.if_id.valid;
+        self.id_ex.id = if !self.if_id.valid {
+            None
+        } else {
+            self.timeline.push(InstTimeline {
+                pc: self.if_id.pc,
+                inst: self.if_id.ir.clone(),
+                issue: self.cycle,
+                execute: None,
+                write_back: None,
+            });
+            Some(self.timeline.len() - 1)
+        };
+
+        // stop fetching once `ecall` is decoded, and not when it's fetched,
+        // so that a stalled `ecall` still waits in IF/ID until it's issued.
+        if self.id_ex.ir.is_ecall() {
+            self.exit = true;
+        }
 
         // control hazard
-        if self.id_ex.ir.is_jump() {
+        if self.predictor.is_none() && self.id_ex.ir.is_jump() {
             self.stall = true;
             self.control_hazard += 1;
         }
     }
 
-    fn ex_cycle(&mut self) {
+    fn ex_cycle(&mut self) -> Result<(), RvError> {
         self.ex_mem.pc = self.id_ex.pc;
         self.ex_mem.npc = self.id_ex.npc;
+        self.ex_mem.pred_taken = self.id_ex.pred_taken;
         self.ex_mem.ir = self.id_ex.ir.clone();
         self.ex_mem.imm_a = self.id_ex.imm_a;
         self.ex_mem.imm_b = self.id_ex.imm_b;
         self.ex_mem.imm_src = self.id_ex.imm_src;
+        self.ex_mem.valid = self.id_ex.valid;
+        self.ex_mem.id = self.id_ex.id;
+        if let Some(id) = self.ex_mem.id {
+            self.timeline[id].execute = Some(self.cycle);
+        }
 
         let alu_in_a = if self.id_ex.ir.alu_use_reg1() {
             self.id_ex.imm_a
@@ -138,10 +429,58 @@ impl CpuState {
         };
 
         self.ex_mem.alu_out = alu(alu_in_a, alu_in_b, self.id_ex.ir.alu_op());
+        if self.trap_on_overflow && overflows(&self.id_ex.ir, alu_in_a, alu_in_b) {
+            return Err(RvError::Overflow {
+                pc: self.id_ex.pc,
+                inst: self.id_ex.ir.debug(),
+            });
+        }
+        let divides = matches!(
+            self.id_ex.ir.alu_op(),
+            AluType::Div | AluType::Divu | AluType::Rem | AluType::Remu
+        );
+        if self.trap_on_div_zero && divides && alu_in_b == 0 {
+            return Err(RvError::DivideByZero {
+                pc: self.id_ex.pc,
+                inst: self.id_ex.ir.debug(),
+            });
+        }
+        // `jalr` clears the lowest bit of its target.
+        if self.id_ex.ir.is_jalr() {
+            self.ex_mem.alu_out &= !1;
+        }
+
+        // csr instructions read the old value and write the new one in EX.
+        if self.id_ex.ir.is_csr() {
+            let csr = self.id_ex.ir.csr();
+            let funct3 = (self.id_ex.ir.binary() >> 12) & 0x7;
+            let src = if funct3 & 0x4 != 0 {
+                self.id_ex.ir.rs1()
+            } else {
+                self.id_ex.imm_a
+            };
+            let old = self.csr(csr);
+            let new = match funct3 & 0x3 {
+                1 => Some(src),
+                2 if self.id_ex.ir.rs1() != 0 => Some(old | src),
+                3 if self.id_ex.ir.rs1() != 0 => Some(old & !src),
+                _ => None,
+            };
+
+            // the counters (0xc00 - 0xcff) are read only.
+            if let Some(new) = new {
+                if csr >> 8 != 0xc {
+                    self.csrs.insert(csr, new);
+                }
+            }
+            self.ex_mem.alu_out = old;
+        }
         self.ex_mem.cond = self.id_ex.ir.branch(self.id_ex.imm_a, self.id_ex.imm_b);
+
+        Ok(())
     }
 
-    fn mem_cycle(&mut self) {
+    fn mem_cycle(&mut self) -> Result<(), RvError> {
         self.mem_wb.pc = self.ex_mem.pc;
         self.mem_wb.npc = self.ex_mem.npc;
         self.mem_wb.ir = self.ex_mem.ir.clone();
@@ -150,20 +489,68 @@ impl CpuState {
         self.mem_wb.imm_src = self.ex_mem.imm_src;
         self.mem_wb.alu_out = self.ex_mem.alu_out;
         self.mem_wb.cond = self.ex_mem.cond;
+        self.mem_wb.valid = self.ex_mem.valid;
+        self.mem_wb.id = self.ex_mem.id;
 
         if self.ex_mem.cond && self.ex_mem.ir.is_jump() {
             self.mem_wb.npc = self.ex_mem.alu_out;
         }
 
-        match self.ex_mem.ir.mem_op() {
-            MemType::Load => {
+        // with split memory, text is only reachable by fetch.
+        let addr = self.ex_mem.alu_out & !3;
+        if self.split_memory
+            && self.ex_mem.ir.mem_op() != MemType::None
+            && self.text.contains(&addr)
+        {
+            return Err(RvError::TextAccess {
+                addr: self.ex_mem.alu_out,
+                inst: self.ex_mem.ir.debug(),
+            });
+        }
+
+        let io = self
+            .mmio
+            .iter_mut()
+            .find(|io| io.range.contains(&self.ex_mem.alu_out));
+        if self.ex_mem.ir.mem_op() != MemType::None
+            && io.is_none()
+            && !self.mem.contains(self.ex_mem.alu_out)
+        {
+            return Err(RvError::MemoryFault(self.ex_mem.alu_out));
+        }
+
+        // a miss, for a load or a store, holds the access in MEM for the
+        // miss penalty. Devices are never cached.
+        if self.ex_mem.ir.mem_op() != MemType::None && io.is_none() {
+            if let Some(cache) = &mut self.cache {
+                if !cache.access(self.ex_mem.alu_out) {
+                    self.mem_wait = cache.config.miss_penalty;
+                }
+            }
+        }
+
+        match (self.ex_mem.ir.mem_op(), io) {
+            (MemType::Load, Some(io)) => {
+                self.mem_wb.mem_out = (io.read)(self.ex_mem.alu_out);
+            }
+            (MemType::Load, None) => {
                 self.mem_wb.mem_out = self.mem.load(self.ex_mem.alu_out);
             }
-            MemType::Store => {
-                self.mem.store(self.ex_mem.alu_out, self.ex_mem.imm_b);
+            (MemType::Store, Some(io)) => {
+                let width = self.ex_mem.ir.mem_width();
+                let mask = u32::MAX >> (32 - width * 8);
+                (io.write)(self.ex_mem.alu_out, self.ex_mem.imm_b & mask);
+                self.mem_wb.mem_out = 0;
+            }
+            (MemType::Store, None) => {
+                self.mem.store(
+                    self.ex_mem.alu_out,
+                    self.ex_mem.imm_b,
+                    self.ex_mem.ir.mem_width(),
+                );
                 self.mem_wb.mem_out = 0;
             }
-            MemType::None => {
+            (MemType::None, _) => {
                 self.mem_wb.mem_out = 0;
             }
         }
@@ -171,86 +558,327 @@ impl CpuState {
         self.mem_wb.write_out = match self.mem_wb.ir.write_back() {
             WBType::Mem => self.mem_wb.mem_out,
             WBType::Alu => self.ex_mem.alu_out,
-            WBType::Pc => self.ex_mem.pc + 4,
+            WBType::Pc => self.ex_mem.pc + self.ex_mem.ir.size(),
             WBType::None => 0,
         };
 
-        // data forwarding.
-        if self.ex_mem.ir.rd() == self.id_ex.ir.rs1() && self.ex_mem.ir.reg_write() {
+        // data forwarding, x0 is hardwired to zero and never forwarded.
+        let forward = self.ex_mem.ir.reg_write() && self.ex_mem.ir.rd() != 0;
+        if forward && self.ex_mem.ir.rd() == self.id_ex.ir.rs1() {
             self.id_ex.imm_a = self.mem_wb.write_out;
         }
-        if self.ex_mem.ir.rd() == self.id_ex.ir.rs2() && self.ex_mem.ir.reg_write() {
+        if forward && self.ex_mem.ir.rd() == self.id_ex.ir.rs2() {
             self.id_ex.imm_b = self.mem_wb.write_out;
         }
+
+        Ok(())
     }
 
-    fn wb_cycle(&mut self) -> Result<RunState, String> {
+    fn wb_cycle(&mut self) -> Result<RunState, RvError> {
         if self.mem_wb.ir.reg_write() {
             self.regs.set(self.mem_wb.ir.rd(), self.mem_wb.write_out);
+
+            if self.mem_wb.ir.rd() == 2 {
+                let low = self.stack_low.unwrap_or(self.stack_top());
+                self.stack_low = Some(low.min(self.mem_wb.write_out));
+            }
         }
 
-        if !self.mem_wb.ir.is_nop() {
+        if self.mem_wb.valid {
             self.pc = self.mem_wb.npc;
+            self.retired += 1;
+        }
+        if let Some(id) = self.mem_wb.id {
+            self.timeline[id].write_back = Some(self.cycle);
         }
 
-        // data forwarding.
-        if self.mem_wb.ir.rd() == self.id_ex.ir.rs1() && self.mem_wb.ir.reg_write() {
+        // data forwarding, x0 is hardwired to zero and never forwarded.
+        let forward = self.mem_wb.ir.reg_write() && self.mem_wb.ir.rd() != 0;
+        if forward && self.mem_wb.ir.rd() == self.id_ex.ir.rs1() {
             self.id_ex.imm_a = self.mem_wb.write_out;
         }
-        if self.mem_wb.ir.rd() == self.id_ex.ir.rs2() && self.mem_wb.ir.reg_write() {
+        if forward && self.mem_wb.ir.rd() == self.id_ex.ir.rs2() {
             self.id_ex.imm_b = self.mem_wb.write_out;
         }
 
         if self.mem_wb.ir.is_ebreak() {
             Ok(RunState::Break)
         } else if self.mem_wb.ir.is_ecall() {
-            if self.regs[10] == 17 {
+            // the syscall number is in a7 and the exit code in a0, but keep
+            // accepting the old `a0 == 17` exit with the code in a1.
+            if self.regs[17] == 93 {
+                Ok(RunState::Exit(self.regs[10]))
+            } else if self.regs[10] == 17 {
                 Ok(RunState::Exit(self.regs[11]))
             } else {
-                return Err("unknown ecall".to_string());
+                // let the caller decide, fetching resumes after the `ecall`
+                // from the next cycle if it keeps stepping.
+                self.exit = false;
+                Ok(RunState::EcallUnknown {
+                    a7: self.regs[17],
+                    a0: self.regs[10],
+                })
             }
         } else {
             Ok(RunState::Running)
         }
     }
 
-    pub fn step(&mut self) -> Result<RunState, String> {
+    pub fn step(&mut self) -> Result<RunState, RvError> {
+        // count the cycle before running it, so that a cycle which ends the
+        // program (or fails) is still included in `cycle()`.
+        self.cycle += 1;
+
+        if self.cycle > 10000 {
+            return Err(RvError::TooManyCycles);
+        }
+
+        // a cache miss in the last cycle stalls every stage.
+        if self.mem_wait > 0 {
+            self.mem_wait -= 1;
+            self.cache_stall += 1;
+            return Ok(RunState::Running);
+        }
+
         let mut state = RunState::Running;
 
-        if self.cycle > 3 {
+        if self.cycle > 4 {
             state = self.wb_cycle()?;
         }
+        if self.cycle > 3 {
+            self.mem_cycle()?;
+        }
         if self.cycle > 2 {
-            self.mem_cycle();
+            self.ex_cycle()?;
         }
         if self.cycle > 1 {
-            self.ex_cycle();
-        }
-        if self.cycle > 0 {
             self.id_cycle();
         }
-        self.if_cycle()?;
+        // an unknown `ecall` may end the program, don't fetch past it yet.
+        if !matches!(state, RunState::EcallUnknown { .. }) {
+            self.if_cycle()?;
+        }
 
-        self.cycle += 1;
+        Ok(state)
+    }
 
-        if self.cycle > 10000 {
-            return Err("too many cycles".to_string());
+    /// Step cycle by cycle, the last item is the exiting cycle or the error.
+    /// `ebreak` doesn't pause the iterator, it only shows up as
+    /// `RunState::Break` in its cycle.
+    pub fn cycles(&mut self) -> Cycles<'_> {
+        Cycles {
+            cpu: self,
+            done: false,
         }
+    }
 
-        Ok(state)
+    /// Decode the instruction at `addr` the way fetch sees it.
+    fn fetch(&self, addr: u32) -> Result<Instruction, RvError> {
+        let mem = if self.split_memory {
+            &self.imem
+        } else {
+            &self.mem
+        };
+        if !mem.contains(addr) {
+            return Err(RvError::MemoryFault(addr));
+        }
+        if self.compressed {
+            // instructions are a sequence of 16-bit parcels, the first
+            // one holds the low bits and tells the length.
+            let parcel = |addr: u32| (mem.load(addr) >> ((addr & 2) * 8)) & 0xffff;
+            let low = parcel(addr);
+            if low & 0x3 == 0x3 {
+                if !mem.contains(addr + 2) {
+                    return Err(RvError::MemoryFault(addr + 2));
+                }
+                Instruction::from_binary(low | (parcel(addr + 2) << 16))
+            } else {
+                Instruction::from_binary(low)
+            }
+        } else {
+            let word = mem.load(addr);
+            Instruction::from_binary(word).and_then(|inst| {
+                if inst.is_compressed() {
+                    Err(RvError::Decode(word))
+                } else {
+                    Ok(inst)
+                }
+            })
+        }
+    }
+
+    /// List the loaded image from the memory itself, so that it also works
+    /// without the names from the assembler. Words in the text are decoded
+    /// and the rest are shown as `.word`; an image with no section
+    /// information, like one from `Program::from_binary`, is decoded
+    /// wherever it holds a valid instruction.
+    pub fn disassemble_program(&self) -> Vec<(u32, String)> {
+        let mut lines = Vec::new();
+        let mut addr = self.loaded.start;
+        while addr < self.loaded.end {
+            let is_text = self.text.contains(&(addr & !3)) || self.text.is_empty();
+            match self.fetch(addr) {
+                Ok(inst) if is_text => {
+                    lines.push((addr, inst.debug()));
+                    addr += inst.size();
+                }
+                _ => {
+                    let mem = if self.split_memory && is_text {
+                        &self.imem
+                    } else {
+                        &self.mem
+                    };
+                    let word = mem.load(addr & !3);
+                    lines.push((addr & !3, format!(".word {:#010x}", word)));
+                    addr = (addr & !3) + 4;
+                }
+            }
+        }
+        lines
+    }
+
+    pub fn load(&mut self, program: &Program) -> Result<(), RvError> {
+        self.load_at(program, 0)
+    }
+
+    /// Place the image at the word aligned `base` instead of address 0.
+    /// Jumps, branches and `la` are pc-relative, so only the `%hi`/`%lo`
+    /// immediates, the entry, the labels and the instruction names move
+    /// with it.
+    pub fn load_at(&mut self, program: &Program, base: u32) -> Result<(), RvError> {
+        if !base.is_multiple_of(4) {
+            return Err(RvError::MisalignedBase(base));
+        }
+        let image = program.mem_at(base);
+        if self.split_memory {
+            // each memory only gets its own words, the others read as zero.
+            let (text, data) = image
+                .iter()
+                .enumerate()
+                .map(|(i, word)| {
+                    if program.is_text(i as u32 * 4) {
+                        (*word, 0)
+                    } else {
+                        (0, *word)
+                    }
+                })
+                .unzip::<_, _, Vec<_>, Vec<_>>();
+            // check both first, so a failed load leaves neither half written.
+            self.imem.check_fits(text.len(), base)?;
+            self.mem.check_fits(data.len(), base)?;
+            self.imem.load_mem(&text, base)?;
+            self.mem.load_mem(&data, base)?;
+        } else {
+            self.mem.load_mem(&image, base)?;
+        }
+        self.text = (0..program.mem().len() as u32 * 4)
+            .step_by(4)
+            .filter(|addr| program.is_text(*addr))
+            .map(|addr| base + addr)
+            .collect();
+        self.loaded = base..base + program.mem().len() as u32 * 4;
+        self.inst_name = program
+            .inst_name()
+            .iter()
+            .map(|(addr, name)| (base + addr, name.clone()))
+            .collect();
+        self.labels = program
+            .labels()
+            .into_iter()
+            .map(|(addr, label)| (base + addr, label))
+            .collect();
+        self.npc = base + program.entry();
+        self.pc = base + program.entry();
+        self.save_image();
+        Ok(())
+    }
+
+    pub fn load_instructions(&mut self, words: &[u32], entry: u32) -> Result<(), RvError> {
+        self.mem.load_mem(words, 0)?;
+        self.imem.load_mem(words, 0)?;
+        self.text = (0..words.len() as u32 * 4).step_by(4).collect();
+        self.loaded = 0..words.len() as u32 * 4;
+        self.inst_name.clear();
+        self.labels.clear();
+        self.npc = entry;
+        self.pc = entry;
+        self.save_image();
+        Ok(())
+    }
+
+    fn save_image(&mut self) {
+        self.image = self.mem.data.to_vec();
+        self.text_image = self.imem.data.to_vec();
+        self.entry = self.pc;
     }
 
-    pub fn load(&mut self, program: &Program) {
-        self.mem.load_mem(program.mem());
-        self.inst_name = program.inst_name().clone();
-        self.npc = program.entry();
-        self.pc = program.entry();
+    /// Put the cpu back to where `load` left it, to run the program again.
+    /// The settings (delays, predictor and cache sizes, memory size, split
+    /// memory, compressed instructions, overflow and division by zero traps,
+    /// compact display and devices) are kept, but the statistics, the
+    /// predictor's counters and the cache start over.
+    pub fn reset(&mut self) {
+        let size = self.mem.data.len() as u32 * 4;
+        let mut mem = Memory::new(size);
+        mem.load_mem(&self.image, 0)
+            .expect("the image was saved from this memory");
+        let mut imem = Memory::new(size);
+        imem.load_mem(&self.text_image, 0)
+            .expect("the image was saved from this memory");
+
+        *self = CpuState {
+            mem,
+            imem,
+            split_memory: self.split_memory,
+            compressed: self.compressed,
+            trap_on_overflow: self.trap_on_overflow,
+            trap_on_div_zero: self.trap_on_div_zero,
+            compact_display: self.compact_display,
+            text: std::mem::take(&mut self.text),
+            image: std::mem::take(&mut self.image),
+            text_image: std::mem::take(&mut self.text_image),
+            loaded: self.loaded.clone(),
+            entry: self.entry,
+            pc: self.entry,
+            npc: self.entry,
+            inst_name: std::mem::take(&mut self.inst_name),
+            labels: std::mem::take(&mut self.labels),
+            fetch_delay: self.fetch_delay,
+            load_delay: self.load_delay,
+            predictor: self
+                .predictor
+                .as_ref()
+                .map(|p| BranchPredictor::new(p.counters.len().trailing_zeros())),
+            cache: self.cache.as_ref().map(|cache| Cache::new(cache.config)),
+            mmio: std::mem::take(&mut self.mmio),
+            ..Default::default()
+        };
+        self.regs.set(2, self.stack_top());
     }
 
     pub fn cycle(&self) -> u32 {
         self.cycle
     }
 
+    /// Address of the next instruction to complete.
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    pub fn reg(&self, index: u32) -> u32 {
+        self.regs[index]
+    }
+
+    /// Value of a csr, the `cycle`, `time` and `instret` counters are read
+    /// from the simulation.
+    pub fn csr(&self, csr: u32) -> u32 {
+        match csr {
+            0xc00 | 0xc01 => self.cycle,
+            0xc02 => self.retired,
+            _ => self.csrs.get(&csr).copied().unwrap_or(0),
+        }
+    }
+
     pub fn data_hazard(&self) -> u32 {
         self.data_hazard
     }
@@ -258,6 +886,191 @@ impl CpuState {
     pub fn control_hazard(&self) -> u32 {
         self.control_hazard
     }
+
+    /// Youngest in-flight instruction which has not written back `reg` yet,
+    /// i.e. the reason an instruction reading `reg` would need forwarding.
+    pub fn producer_of(&self, reg: u32) -> Option<Producer> {
+        [
+            (Stage::IdEx, &self.id_ex),
+            (Stage::ExMem, &self.ex_mem),
+            (Stage::MemWb, &self.mem_wb),
+        ]
+        .into_iter()
+        .find(|(_, state)| state.ir.reg_write() && state.ir.rd() == reg)
+        .map(|(stage, state)| Producer {
+            stage,
+            pc: state.pc,
+            inst: state.ir.clone(),
+        })
+    }
+
+    /// Enable a branch predictor with `2^table_bits` 2-bit counters, or
+    /// disable it with `None` to stall fetch on every jump instead.
+    /// `table_bits` goes from 1 to 20.
+    pub fn set_branch_predictor(&mut self, table_bits: Option<u32>) -> Result<(), RvError> {
+        if let Some(bits) = table_bits {
+            if !(1..=MAX_PREDICTOR_BITS).contains(&bits) {
+                return Err(RvError::InvalidPredictor(bits));
+            }
+        }
+        self.predictor = table_bits.map(BranchPredictor::new);
+        Ok(())
+    }
+
+    pub fn branch_mispredicts(&self) -> u32 {
+        self.branch_mispredicts
+    }
+
+    /// Put a direct-mapped cache of up to 65536 lines in front of the data
+    /// memory, or remove it with `None`. A hit costs nothing more, a load or
+    /// store which misses stalls the pipeline for the `miss_penalty`.
+    pub fn set_cache(&mut self, config: Option<CacheConfig>) -> Result<(), RvError> {
+        if let Some(config) = config {
+            if !config.lines.is_power_of_two()
+                || config.lines > MAX_CACHE_LINES
+                || !config.line_size.is_power_of_two()
+            {
+                return Err(RvError::InvalidCache {
+                    lines: config.lines,
+                    line_size: config.line_size,
+                });
+            }
+        }
+        self.cache = config.map(Cache::new);
+        Ok(())
+    }
+
+    pub fn cache_hits(&self) -> u32 {
+        self.cache.as_ref().map_or(0, |cache| cache.hits)
+    }
+
+    pub fn cache_misses(&self) -> u32 {
+        self.cache.as_ref().map_or(0, |cache| cache.misses)
+    }
+
+    /// Cycles the pipeline spent waiting for cache misses.
+    pub fn cache_stall(&self) -> u32 {
+        self.cache_stall
+    }
+
+    // override the predictor for the branch or `jal` at `pc`.
+    #[cfg(test)]
+    fn force_prediction(&mut self, pc: u32, taken: bool) {
+        self.predictor
+            .as_mut()
+            .expect("branch predictor is disabled")
+            .forced
+            .insert(pc, taken);
+    }
+
+    pub fn fetch_stall(&self) -> u32 {
+        self.fetch_stall
+    }
+
+    pub fn set_fetch_delay(&mut self, delay: u32) {
+        self.fetch_delay = delay;
+    }
+
+    /// Accept the compressed instructions `Instruction` can expand, which
+    /// also allows instructions to be 2 byte aligned.
+    pub fn set_compressed(&mut self, compressed: bool) {
+        self.compressed = compressed;
+    }
+
+    /// Map a device at the byte addresses in `range`: loads there return
+    /// `read(addr)` and stores call `write(addr, value)` with the stored
+    /// bytes, without touching the memory or the cache.
+    pub fn map_io(
+        &mut self,
+        range: Range<u32>,
+        read: impl FnMut(u32) -> u32 + 'static,
+        write: impl FnMut(u32, u32) + 'static,
+    ) {
+        self.mmio.push(Mmio {
+            range,
+            read: Box::new(read),
+            write: Box::new(write),
+        });
+    }
+
+    /// Stop with an error when `add`, `addi`, `sub` or `mul` overflow as
+    /// signed numbers, instead of wrapping around like RISC-V does.
+    pub fn set_trap_on_overflow(&mut self, trap: bool) {
+        self.trap_on_overflow = trap;
+    }
+
+    /// Stop with an error when `div`, `divu`, `rem` or `remu` divide by
+    /// zero, instead of giving the all ones or dividend result RISC-V
+    /// defines.
+    pub fn set_trap_on_div_zero(&mut self, trap: bool) {
+        self.trap_on_div_zero = trap;
+    }
+
+    fn inst_align(&self) -> u32 {
+        if self.compressed {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// Bytes of memory, from the default 32KB up to 64MB. The stack starts
+    /// at its last word. Set it before loading the program, which fails if
+    /// it doesn't fit.
+    pub fn set_memory_size(&mut self, size: u32) -> Result<(), RvError> {
+        if !size.is_multiple_of(4) || !(MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&size) {
+            return Err(RvError::InvalidMemorySize {
+                size,
+                min: MEMORY_SIZE,
+                max: MAX_MEMORY_SIZE,
+            });
+        }
+        self.mem = Memory::new(size);
+        self.imem = Memory::new(size);
+        self.regs.set(2, self.stack_top());
+        self.image.clear();
+        self.text_image.clear();
+        Ok(())
+    }
+
+    /// Only show the registers which are not zero or have a write in flight
+    /// when printing the state, instead of all 32.
+    pub fn set_compact_display(&mut self, compact: bool) {
+        self.compact_display = compact;
+    }
+
+    /// Give instruction fetch its own memory, so that loads and stores can
+    /// no longer reach the text. Set it before loading the program.
+    pub fn set_split_memory(&mut self, split: bool) {
+        self.split_memory = split;
+    }
+
+    /// Extra cycles a load spends in memory, an instruction using its result
+    /// stalls `1 + delay` cycles.
+    pub fn set_load_delay(&mut self, delay: u32) {
+        self.load_delay = delay;
+    }
+
+    /// Every instruction issued so far in program order, with the cycles it
+    /// was issued, executed and written back in, to draw a pipeline diagram.
+    pub fn timeline(&self) -> Vec<InstTimeline> {
+        self.timeline.clone()
+    }
+
+    pub fn instructions_retired(&self) -> u32 {
+        self.retired
+    }
+
+    /// Bytes between the initial stack pointer and the lowest `sp` observed.
+    pub fn stack_usage(&self) -> u32 {
+        self.stack_low
+            .map_or(0, |sp| self.stack_top().saturating_sub(sp))
+    }
+
+    /// The initial stack pointer, the last word of the memory.
+    fn stack_top(&self) -> u32 {
+        self.mem.data.len() as u32 * 4 - 4
+    }
 }
 
 impl Display for CpuState {
@@ -277,15 +1090,17 @@ impl Display for CpuState {
             Blue.paint(self.inst_name.get(&self.pc).unwrap_or(&"???".to_owned())),
             Blue.paint(self.inst_name.get(&self.npc).unwrap_or(&"???".to_owned()))
         )?;
-        write!(f, "{}", self.regs)?;
+        self.regs.fmt_with(f, self.compact_display, |reg| {
+            self.producer_of(reg).map(|producer| producer.stage)
+        })?;
         writeln!(f, "-- IF/ID")?;
-        write!(f, "{}", self.if_id)?;
+        self.if_id.fmt_with(f, &self.labels)?;
         writeln!(f, "-- ID/EX")?;
-        write!(f, "{}", self.id_ex)?;
+        self.id_ex.fmt_with(f, &self.labels)?;
         writeln!(f, "-- EX/MEM")?;
-        write!(f, "{}", self.ex_mem)?;
+        self.ex_mem.fmt_with(f, &self.labels)?;
         writeln!(f, "-- MEM/WB")?;
-        write!(f, "{}", self.mem_wb)?;
+        self.mem_wb.fmt_with(f, &self.labels)?;
 
         Ok(())
     }
@@ -293,34 +1108,138 @@ impl Display for CpuState {
 
 impl Default for Memory {
     fn default() -> Self {
+        Self::new(MEMORY_SIZE)
+    }
+}
+
+impl Memory {
+    fn new(size: u32) -> Self {
         Self {
-            data: [0; 1024 * 8],
+            data: vec![0; size as usize / 4],
         }
     }
 }
 
 impl Memory {
+    fn contains(&self, addr: u32) -> bool {
+        ((addr / 4) as usize) < self.data.len()
+    }
+
     fn load(&self, addr: u32) -> u32 {
         self.data[(addr / 4) as usize]
     }
 
-    fn store(&mut self, addr: u32, data: u32) {
-        self.data[(addr / 4) as usize] = data;
+    // store the low `width` bytes of `data`, memory is little endian so the
+    // byte at the lowest address is the least significant one of its word.
+    fn store(&mut self, addr: u32, data: u32, width: u32) {
+        let index = (addr / 4) as usize;
+        let offset = (addr % 4) & !(width - 1);
+        let shift = offset * 8;
+        let mask = (u32::MAX >> (32 - width * 8)) << shift;
+        self.data[index] = (self.data[index] & !mask) | ((data << shift) & mask);
+    }
+
+    // `words` must fit in the space left from `base`, instead of dropping
+    // the tail.
+    fn check_fits(&self, words: usize, base: u32) -> Result<(), RvError> {
+        let cap = self.data.len().saturating_sub((base / 4) as usize);
+        if words > cap {
+            return Err(RvError::ImageTooLarge { words, cap });
+        }
+        Ok(())
+    }
+
+    fn load_mem(&mut self, data: &[u32], base: u32) -> Result<(), RvError> {
+        self.check_fits(data.len(), base)?;
+        let start = (base / 4) as usize;
+        self.data.fill(0);
+        self.data[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl BranchPredictor {
+    fn new(table_bits: u32) -> Self {
+        // start every counter as weakly not taken.
+        Self {
+            counters: vec![1; 1 << table_bits],
+            #[cfg(test)]
+            forced: HashMap::new(),
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        (pc as usize >> 2) & (self.counters.len() - 1)
+    }
+
+    // `jal` is always taken and its target is known at fetch, while the
+    // target of `jalr` is only known after EX so it is never predicted.
+    fn predict(&self, inst: &Instruction, pc: u32) -> bool {
+        #[cfg(test)]
+        if let Some(&taken) = self.forced.get(&pc) {
+            if inst.is_branch() || inst.is_jal() {
+                return taken;
+            }
+        }
+
+        if inst.is_branch() {
+            self.counters[self.index(pc)] >= 2
+        } else {
+            inst.is_jal()
+        }
+    }
+
+    fn update(&mut self, inst: &Instruction, pc: u32, taken: bool) {
+        if !inst.is_branch() {
+            return;
+        }
+
+        let index = self.index(pc);
+        let counter = &mut self.counters[index];
+        if taken {
+            *counter = (*counter + 1).min(3);
+        } else {
+            *counter = counter.saturating_sub(1);
+        }
+    }
+}
+
+impl Cache {
+    fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            tags: vec![None; config.lines as usize],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn slot(&self, addr: u32) -> (usize, u32) {
+        let line = addr / self.config.line_size;
+        (
+            (line % self.config.lines) as usize,
+            line / self.config.lines,
+        )
     }
 
-    fn load_mem(&mut self, data: &Vec<u32>) {
-        let mut mem = [0; 1024 * 8];
-        for (i, d) in data.iter().enumerate() {
-            mem[i] = *d;
+    // whether `addr` hits, it is in the cache afterwards either way.
+    fn access(&mut self, addr: u32) -> bool {
+        let (index, tag) = self.slot(addr);
+        if self.tags[index] == Some(tag) {
+            self.hits += 1;
+            true
+        } else {
+            self.misses += 1;
+            self.tags[index] = Some(tag);
+            false
         }
-        self.data = mem;
     }
 }
 
 impl Default for Register {
     fn default() -> Self {
         let mut regs = [0; 32];
-        regs[2] = 0x7ffc; // stack point begin with 0x7ffc
+        regs[2] = STACK_TOP; // stack point begin with the last word of the default memory
         Self { regs }
     }
 }
@@ -341,27 +1260,66 @@ impl Register {
     }
 }
 
-impl Display for Register {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for i in 0..4 {
-            for j in 0..8 {
-                let index = i * 8 + j;
+impl Register {
+    // eight registers to a line, the ones `pending` gives a stage for are
+    // marked with it. `compact` leaves out those which are zero and not
+    // pending.
+    fn fmt_with<P>(&self, f: &mut fmt::Formatter<'_>, compact: bool, pending: P) -> fmt::Result
+    where
+        P: Fn(u32) -> Option<Stage>,
+    {
+        let shown = (0..32)
+            .map(|index| (index, if index == 0 { None } else { pending(index) }))
+            .filter(|(index, stage)| !compact || self.regs[*index as usize] != 0 || stage.is_some())
+            .collect::<Vec<_>>();
+        for line in shown.chunks(8) {
+            for (index, stage) in line {
                 write!(
                     f,
-                    "{:>3}: {:08x}, ",
+                    "{:>3}: {:08x}",
                     format!("x{}", index),
-                    self.regs[index]
+                    self.regs[*index as usize]
                 )?;
+                if let Some(stage) = stage {
+                    write!(f, " {}", Yellow.paint(format!("pending@{}", stage)))?;
+                }
+                write!(f, ", ")?;
             }
-            writeln!(f, "")?;
+            writeln!(f)?;
         }
         Ok(())
     }
 }
 
-impl Display for TempState {
+impl Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, false, |_| None)
+    }
+}
+
+impl Display for Stage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::IdEx => "ID/EX",
+            Stage::ExMem => "EX/MEM",
+            Stage::MemWb => "MEM/WB",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl TempState {
+    // branches and `jal` also show their target, with its label if
+    // `labels` has one.
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, labels: &BTreeMap<u32, String>) -> fmt::Result {
         write!(f, "ir: {}, ", Blue.paint(self.ir.to_string()))?;
+        if self.ir.is_branch() || self.ir.is_jal() {
+            let target = self.pc.wrapping_add(self.ir.imm());
+            match labels.get(&target) {
+                Some(label) => write!(f, "target: {:08x} <{}>, ", target, label)?,
+                None => write!(f, "target: {:08x}, ", target)?,
+            }
+        }
         write!(f, "pc: {:08x}, ", self.pc)?;
         write!(f, "npc: {:08x}, ", self.npc)?;
         write!(f, "imm_a: {:08x}, ", self.imm_a)?;
@@ -375,22 +1333,21 @@ impl Display for TempState {
     }
 }
 
-fn alu(a: u32, b: u32, op: AluType) -> u32 {
-    match op {
-        AluType::Add => a.wrapping_add(b),
-        AluType::Sub => a.wrapping_sub(b),
-        AluType::And => a & b,
-        AluType::Or => a | b,
-        AluType::Xor => a ^ b,
-        AluType::Sll => a << b,
-        AluType::Srl => a >> b,
-        AluType::Sra => (a as i32 >> b) as u32,
-        AluType::Slt => ((a as i32) < (b as i32)) as u32,
-        AluType::Sltu => (a < b) as u32,
-        AluType::Mul => a.wrapping_mul(b),
-        AluType::Mulh => ((a as i32 as i64).wrapping_mul(b as i32 as i64) >> 32) as u32,
-        AluType::Mulhu => ((a as u64).wrapping_mul(b as u64) >> 32) as u32,
-        AluType::Bsel => b,
+impl Display for TempState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &BTreeMap::new())
+    }
+}
+
+// whether the signed arithmetic of `add`, `addi`, `sub` or `mul` overflows,
+// other users of the adder (addresses, `auipc`) are not checked.
+fn overflows(inst: &Instruction, a: u32, b: u32) -> bool {
+    let (a, b) = (a as i32, b as i32);
+    match (inst.binary() & 0x7f, inst.alu_op()) {
+        (0x33 | 0x13, AluType::Add) => a.checked_add(b).is_none(),
+        (0x33, AluType::Sub) => a.checked_sub(b).is_none(),
+        (0x33, AluType::Mul) => a.checked_mul(b).is_none(),
+        _ => false,
     }
 }
 
@@ -398,37 +1355,26 @@ fn alu(a: u32, b: u32, op: AluType) -> u32 {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_alu() {
-        assert_eq!(alu(1, 2, AluType::Add), 3);
-        assert_eq!(alu(1, 2, AluType::Sub), 0xffff_ffff);
-        assert_eq!(alu(1, 2, AluType::And), 0);
-        assert_eq!(alu(1, 2, AluType::Or), 3);
-        assert_eq!(alu(1, 2, AluType::Xor), 3);
-        assert_eq!(alu(1, 2, AluType::Sll), 4);
-        assert_eq!(alu(1, 2, AluType::Srl), 0);
-        assert_eq!(alu(1, 2, AluType::Sra), 0);
-        assert_eq!(alu(1, 2, AluType::Slt), 1);
-        assert_eq!(alu(1, 2, AluType::Sltu), 1);
-        assert_eq!(alu(1, 2, AluType::Mul), 2);
-        assert_eq!(alu(0x7fff_ffff, 4, AluType::Mulh), 1);
-        assert_eq!(alu(0x7fff_ffff, 4, AluType::Mulhu), 1);
-        assert_eq!(alu(1, 2, AluType::Bsel), 2);
-    }
+    // step until the program exits, through any `ebreak`.
+    fn run_to_exit(cpu: &mut CpuState) -> u32 {
+        loop {
+            match cpu.step().unwrap() {
+                RunState::Exit(code) => return code,
+                RunState::EcallUnknown { a7, .. } => panic!("unexpected ecall {}", a7),
+                RunState::Running | RunState::Break => {}
+            }
+        }
+    }
 
-    #[test]
-    fn test_alu_signed() {
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Add), 0);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Sub), 0xffff_fffe);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Sll), 0xffff_fffe);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Srl), 0x7fff_ffff);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Sra), 0xffff_ffff);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Slt), 1);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Sltu), 0);
-        assert_eq!(alu(0xffff_ffff, 2, AluType::Mul), 0xffff_fffe);
-        assert_eq!(alu(0xffff_ffff, 2, AluType::Mulh), 0xffff_ffff);
-        assert_eq!(alu(0xffff_ffff, 2, AluType::Mulhu), 1);
-        assert_eq!(alu(0xffff_ffff, 1, AluType::Bsel), 1);
+    // step until the program fails, which it must do before exiting.
+    fn run_to_err(cpu: &mut CpuState) -> RvError {
+        loop {
+            match cpu.step() {
+                Ok(RunState::Running | RunState::Break) => {}
+                Ok(state) => panic!("the program stopped with {:?} instead of failing", state),
+                Err(err) => return err,
+            }
+        }
     }
 
     #[test]
@@ -447,7 +1393,1448 @@ mod tests {
         ";
         let mut cpu = CpuState::default();
         let program = Program::from_buffer(test_str.as_bytes()).unwrap();
-        cpu.load(&program);
+        cpu.load(&program).unwrap();
+        cpu.step().unwrap();
+    }
+
+    #[test]
+    fn test_instructions_retired() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi x1, x0, 1
+        lw x2, 0(x0)
+        add x3, x2, x1
+        beq x0, x0, end
+        addi x4, x0, 4
+        end:
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        // the skipped `addi x4` and the stall bubbles must not be counted.
+        assert_eq!(cpu.instructions_retired(), 6);
+        assert_eq!(cpu.data_hazard(), 1);
+        assert_eq!(cpu.control_hazard(), 1);
+    }
+
+    #[test]
+    fn test_source_nop_retired() {
+        // a `nop` in the program is an instruction, unlike a bubble.
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi x0, x0, 0
+        addi x0, x0, 0
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.instructions_retired(), 4);
+        assert_eq!(cpu.timeline().len(), 4);
+    }
+
+    #[test]
+    fn test_stack_usage() {
+        let test_str = r"
+        .globl main
+        .data
+        buf: .word 1 2 3
+        .text
+        main:
+        addi sp, sp, -16
+        sw x0, 0(sp)
+        addi sp, sp, -8
+        addi sp, sp, 24
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+        assert_eq!(cpu.stack_usage(), 0);
+        run_to_exit(&mut cpu);
+
+        assert_eq!(program.text_size(), 6 * 4);
+        assert_eq!(program.data_size(), 3 * 4);
+        assert_eq!(cpu.stack_usage(), 24);
+    }
+
+    #[test]
+    fn test_load_instructions() {
+        let words = [
+            0x00500093, // addi x1, x0, 5
+            0x00308113, // addi x2, x1, 3
+            0x402101b3, // sub x3, x2, x2
+            0x01100513, // addi a0, x0, 17
+            0x00100593, // addi a1, x0, 1
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 1);
+        assert_eq!(cpu.regs[1], 5);
+        assert_eq!(cpu.regs[2], 8);
+        assert_eq!(cpu.regs[3], 0);
+        assert_eq!(cpu.instructions_retired(), 6);
+    }
+
+    #[test]
+    fn test_fetch_delay() {
+        let words = [
+            0x00500093, // addi x1, x0, 5
+            0x01100513, // addi a0, x0, 17
+            0x00000073, // ecall
+        ];
+
+        let run = |delay| {
+            let mut cpu = CpuState::default();
+            cpu.set_fetch_delay(delay);
+            cpu.load_instructions(&words, 0).unwrap();
+            run_to_exit(&mut cpu);
+            assert_eq!(cpu.regs[1], 5);
+            (cpu.cycle(), cpu.fetch_stall())
+        };
+
+        assert_eq!(run(0), (7, 0));
+        assert_eq!(run(2), (7 + 3 * 2, 3 * 2));
+    }
+
+    #[test]
+    fn test_load_delay() {
+        let words = [
+            0x00500093, // addi x1, x0, 5
+            0x00102023, // sw x1, 0(x0)
+            0x00002103, // lw x2, 0(x0)
+            0x00110193, // addi x3, x2, 1
+            0x01100513, // addi a0, x0, 17
+            0x00000073, // ecall
+        ];
+
+        let run = |delay| {
+            let mut cpu = CpuState::default();
+            cpu.set_load_delay(delay);
+            cpu.load_instructions(&words, 0).unwrap();
+            run_to_exit(&mut cpu);
+            assert_eq!(cpu.regs[3], 6);
+            (cpu.cycle(), cpu.data_hazard())
+        };
+
+        assert_eq!(run(0), (11, 1));
+        for delay in 1..5 {
+            assert_eq!(run(delay), (11 + delay, 1 + delay));
+        }
+    }
+
+    #[test]
+    fn test_la() {
+        // `msg` sits at 0x800, so the low part of the offset is negative.
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        la a0, msg
+        lw a1, 0(a0)
+        addi a0, x0, 17
+        ecall
+        .data
+        .zero 2028
+        msg: .word 42
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 42);
+    }
+
+    #[test]
+    fn test_call_and_tail() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        call func
+        addi a1, a1, 1
+        tail done
+        func:
+        addi a1, x0, 7
+        jalr x0, 0(ra)
+        done:
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 8);
+        assert_eq!(cpu.regs[1], 8); // return address after `call`
+    }
+
+    #[test]
+    fn test_jalr() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        la t0, target
+        addi t0, t0, -3
+        jalr ra, t0, 4
+        addi a0, x0, 1
+        target:
+        addi a7, x0, 93
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        // `target + 1` is rounded down to `target`.
+        assert_eq!(code, 0);
+        assert_eq!(cpu.regs[1], 16);
+    }
+
+    #[test]
+    fn test_load_at() {
+        let test_str = r"
+        .globl main
+        .data
+        value: .word 41
+        .text
+        main:
+        la t0, value
+        lw a0, 0(t0)
+        addi a0, a0, 1
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        cpu.load_at(&program, 0x1000).unwrap();
+
+        assert_eq!(cpu.pc(), 0x1004);
+        assert_eq!(cpu.mem.load(0x1000), 41);
+        assert_eq!(cpu.mem.load(0), 0);
+        let inst = Instruction::from_binary(cpu.mem.load(cpu.pc())).unwrap();
+        assert_eq!(inst.debug(), "auipc x5, 0x0");
+        assert_eq!(cpu.inst_name.get(&0x1004).unwrap(), "la t0, value");
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 42);
+    }
+
+    #[test]
+    fn test_load_at_hi_lo() {
+        // absolute addresses follow the image, `%lo` is negative at 0x1800.
+        let test_str = r"
+        .globl main
+        .data
+        value: .word 41
+        .text
+        main:
+        lui t0, %hi(value)
+        lw a0, %lo(value)(t0)
+        addi a0, a0, 1
+        sw a0, %lo(value)(t0)
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        for base in [0, 0x1000, 0x1800] {
+            let mut cpu = CpuState::default();
+            cpu.load_at(&program, base).unwrap();
+            assert_eq!(run_to_exit(&mut cpu), 42, "at {:#x}", base);
+            assert_eq!(cpu.mem.load(base), 42, "at {:#x}", base);
+        }
+        // the program itself is left as assembled for address 0.
+        assert_eq!(program.mem_at(0), *program.mem());
+    }
+
+    #[test]
+    fn test_load_at_misaligned() {
+        let program = Program::from_buffer(".globl main\n.text\nmain: ecall".as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        assert_eq!(
+            cpu.load_at(&program, 0x1002),
+            Err(RvError::MisalignedBase(0x1002))
+        );
+        assert_eq!(
+            cpu.load_at(&program, 0x1002).unwrap_err().to_string(),
+            "load base 0x1002 is not word aligned"
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let summary = run(r"
+        .globl main
+        .text
+        main:
+        addi a1, x0, 5
+        addi a0, x0, 17
+        ecall
+        ")
+        .unwrap();
+        assert_eq!(summary.exit_code, 5);
+        assert_eq!(summary.regs[10], 17);
+        assert_eq!(summary.instructions, 3);
+
+        let err = run(".globl main\n.text\nmain:\naddi a7, x0, 1\necall").unwrap_err();
+        assert_eq!(err, RvError::UnknownEcall { a7: 1, a0: 0 });
+        assert_eq!(err.to_string(), "unknown ecall: a7 = 1, a0 = 0");
+        assert!(run("addi a0, x0, 1").is_err());
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let test_str = r"
+        .globl main
+        .data
+        value: .word 41
+        .text
+        main:
+        lw a0, 0(x0)
+        addi a0, a0, 1
+        sub a1, a0, a0
+        addi a7, x0, 93
+        ecall
+        ";
+        let source = ["lw", "addi", "sub", "addi", "ecall"];
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        cpu.load(&program).unwrap();
+
+        let lines = cpu.disassemble_program();
+        assert_eq!(lines[0], (0, ".word 0x00000029".to_string()));
+        let mnemonics = lines[1..]
+            .iter()
+            .map(|(_, line)| line.split_whitespace().next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(mnemonics, source);
+        assert_eq!(lines[2], (8, "addi x10, x10, 1".to_string()));
+
+        // without the sections every valid word is taken as an instruction.
+        let bytes = program
+            .mem()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        let image = Program::from_binary(&bytes, crate::Endian::Little).unwrap();
+        let mut cpu = CpuState::default();
+        cpu.load(&image).unwrap();
+        let listed = cpu.disassemble_program();
+        assert_eq!(listed[1..], lines[1..]);
+    }
+
+    #[test]
+    fn test_memory_size() {
+        // a data array past the default 32KB, then the code.
+        let test_str = r"
+        .globl main
+        .data
+        array: .zero 40000
+        last: .word 7
+        .text
+        main:
+        la t0, last
+        lw a0, 0(t0)
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        assert_eq!(
+            cpu.load(&program).unwrap_err(),
+            RvError::ImageTooLarge {
+                words: 10006,
+                cap: 8192
+            }
+        );
+
+        cpu.set_memory_size(64 * 1024).unwrap();
+        // the stack moves to the end of the memory, past the array.
+        assert_eq!(cpu.reg(2), 64 * 1024 - 4);
+        cpu.load(&program).unwrap();
+        for (i, word) in program.mem().iter().enumerate() {
+            assert_eq!(cpu.mem.load(i as u32 * 4), *word);
+        }
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 7);
+
+        cpu.reset();
+        assert_eq!(cpu.mem.data.len(), 16 * 1024);
+        assert_eq!(cpu.reg(2), 64 * 1024 - 4);
+        for size in [1024, 64 * 1024 + 2, u32::MAX - 3] {
+            assert_eq!(
+                cpu.set_memory_size(size),
+                Err(RvError::InvalidMemorySize {
+                    size,
+                    min: MEMORY_SIZE,
+                    max: MAX_MEMORY_SIZE
+                })
+            );
+        }
+        assert_eq!(cpu.mem.data.len(), 16 * 1024);
+    }
+
+    #[test]
+    fn test_image_too_large() {
+        let test_str = ".globl main\n.text\nmain:\necall\n.data\n.zero 32768\n";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(program.mem().len(), 8193);
+
+        let mut cpu = CpuState::default();
+        assert_eq!(
+            cpu.load(&program).unwrap_err().to_string(),
+            "program image (8193 words) exceeds memory (8192 words)"
+        );
+
+        // nothing is left past the end of memory.
+        let test_str = ".globl main\n.text\nmain:\necall\n";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        assert_eq!(
+            cpu.load_at(&program, 0x8000).unwrap_err(),
+            RvError::ImageTooLarge { words: 1, cap: 0 }
+        );
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(
+            &[
+                0x000102b7, // lui t0, 0x10
+                0x0002a503, // lw a0, 0(t0)
+                0x0000006f, // jal x0, 0
+            ],
+            0,
+        )
+        .unwrap();
+        let err = run_to_err(&mut cpu);
+        assert_eq!(err, RvError::MemoryFault(0x10000));
+
+        // running off the end of the memory faults in fetch.
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(
+            &[
+                0x000080b7, // lui x1, 0x8
+                0x00008067, // jalr x0, 0(x1)
+            ],
+            0,
+        )
+        .unwrap();
+        let err = run_to_err(&mut cpu);
+        assert_eq!(err, RvError::MemoryFault(0x8000));
+    }
+
+    #[test]
+    fn test_compact_display() {
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(
+            &[
+                0x02a00513, // addi a0, x0, 42
+                0x00100593, // addi a1, x0, 1
+                0x05d00893, // addi a7, x0, 93
+                0x00000073, // ecall
+            ],
+            0,
+        )
+        .unwrap();
+        cpu.set_compact_display(true);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        // a0 is written, a1 is in flight and a2 was never touched.
+        let out = strip_ansi(&cpu.to_string());
+        assert!(out.contains("x10: 0000002a, "));
+        assert!(out.contains("x11: 00000000 pending@MEM/WB, "));
+        assert!(!out.contains("x12:"));
+        assert!(out.contains(" x2: 00007ffc"));
+
+        cpu.set_compact_display(false);
+        let out = strip_ansi(&cpu.to_string());
+        assert!(out.contains("x12: 00000000"));
+        assert!(out.contains("x11: 00000000 pending@MEM/WB, "));
+        assert!(out.contains("x17: 00000000 pending@EX/MEM, "));
+        assert!(out.contains(" x0: 00000000, "));
+    }
+
+    #[test]
+    fn test_display_jump_target() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        jal ra, func
+        addi a7, x0, 93
+        ecall
+        func:
+        jalr x0, 0(ra)
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        cpu.load(&program).unwrap();
         cpu.step().unwrap();
+
+        let out = strip_ansi(&cpu.to_string());
+        let line = out.lines().find(|line| line.contains("jal x1")).unwrap();
+        assert!(line.contains("target: 0000000c <func>, "));
+    }
+
+    fn strip_ansi(text: &str) -> String {
+        let mut out = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|&c| c == 'm');
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_split_memory() {
+        let test_str = r"
+        .globl main
+        .data
+        value: .word 7
+        .text
+        main:
+        la t0, value
+        lw a0, 0(t0)
+        la t1, main
+        sw a0, 0(t1)
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        // shared memory lets the program overwrite its own first instruction.
+        let mut cpu = CpuState::default();
+        cpu.load(&program).unwrap();
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 7);
+        assert_eq!(cpu.mem.load(4), 7);
+
+        let mut cpu = CpuState::default();
+        cpu.set_split_memory(true);
+        cpu.load(&program).unwrap();
+        assert_eq!(cpu.imem.load(0), 0);
+        assert_eq!(cpu.mem.load(4), 0);
+        let err = run_to_err(&mut cpu);
+        assert_eq!(
+            err,
+            RvError::TextAccess {
+                addr: 4,
+                inst: "sw x10, 0(x6)".to_string()
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "data access to text address 0x4 in sw x10, 0(x6)"
+        );
+        assert_eq!(cpu.reg(10), 7);
+
+        // a load which doesn't fit leaves both memories as they were.
+        let (text, data) = (cpu.imem.data.clone(), cpu.mem.data.clone());
+        assert!(cpu.load_at(&program, 0x7ff8).is_err());
+        assert_eq!(cpu.imem.data, text);
+        assert_eq!(cpu.mem.data, data);
+    }
+
+    #[test]
+    fn test_cycles() {
+        let words = [
+            0x00500093, // addi x1, x0, 5
+            0x05d00893, // addi a7, x0, 93
+            0x00108513, // addi a0, x1, 1
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+
+        let traces = cpu.cycles().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(traces.len(), 8);
+        assert_eq!(traces[0].cycle, 1);
+        assert!(traces[..7].iter().all(|t| t.state == RunState::Running));
+        let last = traces.last().unwrap();
+        assert_eq!(last.state, RunState::Exit(6));
+        assert_eq!(last.cycle, cpu.cycle());
+        assert_eq!(last.retired, 4);
+
+        // an error is the last item.
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&[0x0000006f], 0).unwrap(); // jal x0, 0
+        let last = cpu.cycles().last().unwrap();
+        assert_eq!(last, Err(RvError::TooManyCycles));
+    }
+
+    #[test]
+    fn test_ebreak() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi a0, x0, 3
+        ebreak
+        addi a0, a0, 4
+        addi a7, x0, 93
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+
+        let mut states = Vec::new();
+        loop {
+            match cpu.step().unwrap() {
+                RunState::Running => {}
+                RunState::Break => states.push((RunState::Break, cpu.reg(10))),
+                state => {
+                    states.push((state, cpu.reg(10)));
+                    break;
+                }
+            }
+        }
+        assert_eq!(states, vec![(RunState::Break, 3), (RunState::Exit(7), 7)]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let test_str = r"
+        .globl main
+        .data
+        counter: .word 5
+        .text
+        main:
+        la t0, counter
+        lw a0, 0(t0)
+        addi a0, a0, 1
+        sw a0, 0(t0)
+        addi sp, sp, -8
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        cpu.set_branch_predictor(Some(2)).unwrap();
+        cpu.load(&program).unwrap();
+
+        let run = |cpu: &mut CpuState| {
+            let code = run_to_exit(cpu);
+            let regs = (0..32).map(|i| cpu.reg(i)).collect::<Vec<_>>();
+            (code, regs, cpu.cycle(), cpu.instructions_retired())
+        };
+
+        let first = run(&mut cpu);
+        cpu.reset();
+        assert_eq!(cpu.reg(2), STACK_TOP);
+        assert_eq!(cpu.cycle(), 0);
+        // the store of the first run is undone too.
+        let second = run(&mut cpu);
+        assert_eq!(first.0, 6);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_cache() {
+        // sum 32 words twice, every load is used right away.
+        let test_str = r"
+        .globl main
+        .data
+        array: .zero 128
+        .text
+        main:
+        addi s0, x0, 2
+        outer:
+        la t0, array
+        addi t1, x0, 32
+        inner:
+        lw t2, 0(t0)
+        add a0, a0, t2
+        addi t0, t0, 4
+        addi t1, t1, -1
+        bne t1, x0, inner
+        addi s0, s0, -1
+        bne s0, x0, outer
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let run = |lines| {
+            let mut cpu = CpuState::default();
+            cpu.set_cache(Some(CacheConfig {
+                lines,
+                line_size: 16,
+                miss_penalty: 5,
+            }))
+            .unwrap();
+            cpu.load(&program).unwrap();
+            run_to_exit(&mut cpu);
+            (cpu.cache_hits(), cpu.cache_misses(), cpu.cycle())
+        };
+
+        // 128 bytes fit in 8 lines of 16 bytes, only the first pass misses
+        // once per line.
+        let (hits, misses, fits) = run(8);
+        assert_eq!((hits, misses), (56, 8));
+        // with 4 lines the second pass misses on every line again.
+        let (hits, misses, thrashes) = run(4);
+        assert_eq!((hits, misses), (48, 16));
+        assert_eq!(thrashes - fits, 8 * 5);
+
+        let mut cpu = CpuState::default();
+        let config = CacheConfig {
+            lines: 3,
+            line_size: 16,
+            miss_penalty: 5,
+        };
+        assert_eq!(
+            cpu.set_cache(Some(config)),
+            Err(RvError::InvalidCache {
+                lines: 3,
+                line_size: 16
+            })
+        );
+        let config = CacheConfig {
+            lines: 1 << 17,
+            ..config
+        };
+        assert_eq!(
+            cpu.set_cache(Some(config)),
+            Err(RvError::InvalidCache {
+                lines: 1 << 17,
+                line_size: 16
+            })
+        );
+    }
+
+    #[test]
+    fn test_cache_miss_stall() {
+        // neither the load nor the store is waited for by a later
+        // instruction, their misses stall the pipeline anyway.
+        let test_str = r"
+        .globl main
+        .data
+        array: .zero 128
+        .text
+        main:
+        la t0, array
+        lw t1, 0(t0)
+        addi a1, x0, 1
+        addi a2, x0, 2
+        sw a1, 64(t0)
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        let mut baseline = CpuState::default();
+        baseline.load(&program).unwrap();
+        run_to_exit(&mut baseline);
+
+        let mut cpu = CpuState::default();
+        cpu.set_cache(Some(CacheConfig {
+            lines: 4,
+            line_size: 16,
+            miss_penalty: 5,
+        }))
+        .unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.cache_misses(), 2);
+        assert_eq!(cpu.cache_stall(), 2 * 5);
+        assert_eq!(cpu.cycle(), baseline.cycle() + 2 * 5);
+        assert_eq!(cpu.data_hazard(), baseline.data_hazard());
+        assert_eq!(cpu.mem.load(program.addr_of("array").unwrap() + 64), 1);
+    }
+
+    #[test]
+    fn test_misaligned_fetch() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        la t0, target
+        jalr ra, t0, 2
+        addi a7, x0, 93
+        ecall
+        target:
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        for predict in [None, Some(2)] {
+            let mut cpu = CpuState::default();
+            cpu.set_branch_predictor(predict).unwrap();
+            cpu.load(&program).unwrap();
+            let err = run_to_err(&mut cpu);
+            assert_eq!(err, RvError::MisalignedFetch { target: 0x16 });
+            assert_eq!(err.to_string(), "instruction address misaligned: 0x16");
+        }
+
+        // a not taken branch to a misaligned target doesn't trap, even when
+        // it would be predicted taken.
+        let words = [
+            0x00100093, // addi x1, x0, 1
+            0x00008163, // beq x1, x0, 2
+            0x05d00893, // addi a7, x0, 93
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.set_branch_predictor(Some(2)).unwrap();
+        cpu.force_prediction(4, true);
+        cpu.load_instructions(&words, 0).unwrap();
+        run_to_exit(&mut cpu);
+    }
+
+    #[test]
+    fn test_compressed() {
+        // 0x00: c.addi a0, 5
+        // 0x02: addi a7, x0, 93
+        // 0x06: c.jal f
+        // 0x08: c.mv a1, ra
+        // 0x0a: c.beqz a2, end
+        // 0x0c: c.addi a0, 1
+        // 0x0e: end: ecall
+        // 0x12: c.nop
+        // 0x14: f: c.addi a0, 2
+        // 0x16: jalr x0, ra, 0
+        let words = [
+            0x08930515, 0x203905d0, 0xc2118586, 0x00730505, 0x00010000, 0x80670509, 0x00000000,
+        ];
+
+        for predict in [None, Some(2)] {
+            let mut cpu = CpuState::default();
+            cpu.set_compressed(true);
+            cpu.set_branch_predictor(predict).unwrap();
+            cpu.load_instructions(&words, 0).unwrap();
+            let code = run_to_exit(&mut cpu);
+            assert_eq!(code, 7);
+            // `c.jal` links the address right after its 2 bytes.
+            assert_eq!(cpu.reg(11), 8);
+            assert_eq!(cpu.instructions_retired(), 8);
+        }
+
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+        assert_eq!(cpu.step().err(), Some(RvError::Decode(0x08930515)));
+    }
+
+    #[test]
+    fn test_trap_on_div_zero() {
+        let words = [
+            0x00700113, // addi x2, x0, 7
+            0x020140b3, // div x1, x2, x0
+            0x020161b3, // rem x3, x2, x0
+            0x05d00893, // addi a7, x0, 93
+            0x00000073, // ecall
+        ];
+
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+        run_to_exit(&mut cpu);
+        assert_eq!(cpu.reg(1), 0xffff_ffff);
+        assert_eq!(cpu.reg(3), 7);
+
+        let mut cpu = CpuState::default();
+        cpu.set_trap_on_div_zero(true);
+        cpu.load_instructions(&words, 0).unwrap();
+        let err = run_to_err(&mut cpu);
+        assert_eq!(
+            err,
+            RvError::DivideByZero {
+                pc: 4,
+                inst: "div x1, x2, x0".to_string()
+            }
+        );
+        assert_eq!(cpu.reg(1), 0);
+    }
+
+    #[test]
+    fn test_trap_on_overflow() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi t0, x0, -1
+        srli t0, t0, 1
+        addi t1, x0, 1
+        add a0, t0, t1
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        let mut cpu = CpuState::default();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+        assert_eq!(cpu.reg(10), 0x8000_0000);
+
+        let mut cpu = CpuState::default();
+        cpu.set_trap_on_overflow(true);
+        cpu.load(&program).unwrap();
+        let err = run_to_err(&mut cpu);
+        assert_eq!(
+            err,
+            RvError::Overflow {
+                pc: 0xc,
+                inst: "add x10, x5, x6".to_string()
+            }
+        );
+
+        // unsigned wrap around is fine.
+        let mut cpu = CpuState::default();
+        cpu.set_trap_on_overflow(true);
+        cpu.load_instructions(
+            &[
+                0xfff00293, // addi t0, x0, -1
+                0x00128313, // addi t1, t0, 1
+                0x05d00893, // addi a7, x0, 93
+                0x00000073, // ecall
+            ],
+            0,
+        )
+        .unwrap();
+        run_to_exit(&mut cpu);
+        assert_eq!(cpu.reg(6), 0);
+    }
+
+    #[test]
+    fn test_mmio() {
+        use std::{cell::RefCell, rc::Rc};
+
+        // a putchar at 0x10000000 and a counter at 0x10000004.
+        let test_str = r"
+        .globl main
+        .data
+        value: .word 7
+        .text
+        main:
+        lui s0, 0x10000
+        addi a0, x0, 0x16f
+        sb a0, 0(s0)
+        addi a0, x0, 0x6b
+        sw a0, 0(s0)
+        la t0, value
+        lw a1, 0(t0)
+        lw a0, 4(s0)
+        lw a0, 4(s0)
+        add a0, a0, a1
+        addi a7, x0, 93
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let writes = out.clone();
+        let mut reads = 0;
+
+        let mut cpu = CpuState::default();
+        cpu.set_cache(Some(CacheConfig {
+            lines: 4,
+            line_size: 16,
+            miss_penalty: 5,
+        }))
+        .unwrap();
+        cpu.map_io(
+            0x1000_0000..0x1000_0008,
+            move |addr| {
+                assert_eq!(addr, 0x1000_0004);
+                reads += 1;
+                reads
+            },
+            move |addr, value| writes.borrow_mut().push((addr, value)),
+        );
+        cpu.load(&program).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 9);
+        // `sb` only writes its low byte.
+        assert_eq!(
+            *out.borrow(),
+            vec![(0x1000_0000, b'o' as u32), (0x1000_0000, b'k' as u32)]
+        );
+        // only the load of `value` went through the cache.
+        assert_eq!((cpu.cache_hits(), cpu.cache_misses()), (0, 1));
+    }
+
+    #[test]
+    fn test_write_after_write() {
+        let test_str = r"
+        .globl main
+        .data
+        val: .word 42
+        .text
+        main:
+        la t0, val
+        addi x1, x0, 1
+        lw x1, 0(t0)
+        add x2, x1, x0
+        lw x3, 0(t0)
+        addi x3, x0, 5
+        add x4, x3, x0
+        addi a0, x0, 17
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        // the youngest write to a register must win, whatever its latency.
+        for delay in 0..3 {
+            let mut cpu = CpuState::default();
+            cpu.set_load_delay(delay);
+            cpu.load(&program).unwrap();
+            run_to_exit(&mut cpu);
+
+            assert_eq!(cpu.regs[2], 42);
+            assert_eq!(cpu.regs[4], 5);
+            assert_eq!(cpu.regs[1], 42);
+            assert_eq!(cpu.regs[3], 5);
+        }
+    }
+
+    #[test]
+    fn test_fence() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi x1, x0, 1
+        fence
+        fence.i
+        addi x1, x1, 1
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.regs[1], 2);
+        assert_eq!(cpu.instructions_retired(), 6);
+    }
+
+    #[test]
+    fn test_csr() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi x1, x0, 1
+        csrr a1, cycle
+        csrrw a2, 0x340, x1
+        csrrsi a3, 0x340, 6
+        csrrc a4, mscratch_none, x0
+        csrr a5, 0x340
+        csrrw a6, instret, x1
+        addi a0, x0, 17
+        ecall
+        ";
+        assert!(Program::from_buffer(test_str.as_bytes()).is_err());
+
+        let test_str = test_str.replace("mscratch_none", "0x340");
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+
+        // `csrr a1, cycle` reads the cycle it's executed in.
+        let mut read_at = None;
+        while let RunState::Running = cpu.step().unwrap() {
+            if cpu.ex_mem.ir.binary() == 0xc00025f3 {
+                assert_eq!(cpu.ex_mem.alu_out, cpu.cycle());
+                read_at = Some(cpu.cycle());
+            }
+        }
+
+        assert_eq!(Some(cpu.regs[11]), read_at);
+        assert_eq!(cpu.regs[12], 0);
+        assert_eq!(cpu.regs[13], 1);
+        assert_eq!(cpu.regs[14], 7);
+        assert_eq!(cpu.regs[15], 7);
+        // `instret` is read while the `csrr a5` before it is still in MEM, and
+        // writing it has no effect.
+        assert_eq!(cpu.regs[16], 5);
+        assert_eq!(cpu.csr(0x340), 7);
+        assert_eq!(cpu.csr(0xc02), cpu.instructions_retired());
+    }
+
+    #[test]
+    fn test_load_use_consumers() {
+        let test_str = r"
+        .globl main
+        .data
+        val: .word 42 0
+        .text
+        main:
+        la t0, val
+        lw x1, 0(t0)
+        add x2, x1, x0
+        add x3, x0, x1
+        add x4, x1, x1
+        lw x28, 0(t0)
+        addi x29, x0, 1
+        add x30, x28, x29
+        sw x28, 4(t0)
+        addi a0, x0, 17
+        ecall
+        ";
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        // consumers right after the load, and one or two slots later.
+        for delay in 0..3 {
+            let mut cpu = CpuState::default();
+            cpu.set_load_delay(delay);
+            cpu.load(&program).unwrap();
+            run_to_exit(&mut cpu);
+
+            assert_eq!(cpu.regs[2], 42);
+            assert_eq!(cpu.regs[3], 42);
+            assert_eq!(cpu.regs[4], 84);
+            assert_eq!(cpu.regs[30], 43);
+            assert_eq!(cpu.mem.load(4), 42);
+            assert_eq!(cpu.data_hazard(), 1 + delay);
+        }
+    }
+
+    #[test]
+    fn test_auipc_after_stall() {
+        let words = [
+            0x00002083, // lw x1, 0(x0)
+            0x00008133, // add x2, x1, x0
+            0x00001197, // auipc x3, 1
+            0x0040026f, // jal x4, 4
+            0x00000297, // auipc x5, 0
+            0x01100513, // addi a0, x0, 17
+            0x00000073, // ecall
+        ];
+
+        // the stalled `auipc` still adds its own address, not the one being
+        // fetched once it moves on, and so do the `jal` and `auipc` behind it.
+        for (load_delay, fetch_delay) in [(0, 0), (1, 0), (2, 0), (0, 3)] {
+            for predict in [None, Some(2)] {
+                let mut cpu = CpuState::default();
+                cpu.set_load_delay(load_delay);
+                cpu.set_fetch_delay(fetch_delay);
+                cpu.set_branch_predictor(predict).unwrap();
+                cpu.load_instructions(&words, 0).unwrap();
+                run_to_exit(&mut cpu);
+
+                assert!(cpu.data_hazard() + cpu.fetch_stall() > 0);
+                assert_eq!(cpu.regs[2], 0x00002083);
+                assert_eq!(cpu.regs[3], 0x1008);
+                assert_eq!(cpu.regs[4], 0x10);
+                assert_eq!(cpu.regs[5], 0x10);
+            }
+        }
+    }
+
+    #[test]
+    fn test_producer_of() {
+        let words = [
+            0x00500093, // addi x1, x0, 5
+            0x00100113, // addi x2, x0, 1
+            0x01100513, // addi a0, x0, 17
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+
+        cpu.step().unwrap();
+        assert!(cpu.producer_of(1).is_none());
+
+        for stage in [Stage::IdEx, Stage::ExMem, Stage::MemWb] {
+            cpu.step().unwrap();
+            let producer = cpu.producer_of(1).unwrap();
+            assert_eq!(producer.stage, stage);
+            assert_eq!(producer.pc, 0);
+            assert_eq!(producer.inst.binary(), 0x00500093);
+        }
+
+        cpu.step().unwrap();
+        assert!(cpu.producer_of(1).is_none());
+        assert_eq!(cpu.producer_of(2).unwrap().stage, Stage::MemWb);
+        assert!(cpu.producer_of(0).is_none());
+    }
+
+    #[test]
+    fn test_x0_forwarding() {
+        let words = [
+            0x00000033, // add x0, x0, x0
+            0x000000b3, // add x1, x0, x0
+            0x00500013, // addi x0, x0, 5
+            0x00000133, // add x2, x0, x0
+            0x00500013, // addi x0, x0, 5
+            0x00000013, // addi x0, x0, 0
+            0x000001b3, // add x3, x0, x0
+            0x01100513, // addi a0, x0, 17
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+        cpu.regs.set(1, 0xdead);
+        cpu.regs.set(2, 0xdead);
+        cpu.regs.set(3, 0xdead);
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.regs[0], 0);
+        assert_eq!(cpu.regs[1], 0);
+        assert_eq!(cpu.regs[2], 0);
+        assert_eq!(cpu.regs[3], 0);
+    }
+
+    #[test]
+    fn test_predictor_bits() {
+        let mut cpu = CpuState::default();
+        for bits in [0, 21, 40, 64] {
+            assert_eq!(
+                cpu.set_branch_predictor(Some(bits)),
+                Err(RvError::InvalidPredictor(bits))
+            );
+        }
+        assert!(cpu.predictor.is_none());
+        cpu.set_branch_predictor(Some(20)).unwrap();
+        assert_eq!(cpu.predictor.unwrap().counters.len(), 1 << 20);
+    }
+
+    #[test]
+    fn test_branch_predictor() {
+        let test_str = include_str!("../tests/3.s");
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        let mut baseline = CpuState::default();
+        baseline.load(&program).unwrap();
+        run_to_exit(&mut baseline);
+
+        let mut cpu = CpuState::default();
+        cpu.set_branch_predictor(Some(4)).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.regs.regs, baseline.regs.regs);
+        assert_eq!(cpu.regs[2], 55);
+        assert_eq!(baseline.control_hazard(), 10);
+        assert_eq!(baseline.branch_mispredicts(), 0);
+        // only the first iteration, which warms the counter up, and the loop
+        // exit are mispredicted.
+        assert_eq!(cpu.control_hazard(), 0);
+        assert_eq!(cpu.branch_mispredicts(), 2);
+        assert!(cpu.cycle() < baseline.cycle());
+    }
+
+    #[test]
+    fn test_force_prediction() {
+        let words = [
+            0x00100093, // addi x1, x0, 1
+            0x00008463, // beq x1, x0, 8
+            0x00200113, // addi x2, x0, 2
+            0x01100513, // addi a0, x0, 17
+            0x00000073, // ecall
+        ];
+
+        let run = |forced| {
+            let mut cpu = CpuState::default();
+            cpu.set_branch_predictor(Some(4)).unwrap();
+            if let Some(taken) = forced {
+                cpu.force_prediction(4, taken);
+            }
+            cpu.load_instructions(&words, 0).unwrap();
+            run_to_exit(&mut cpu);
+            assert_eq!(cpu.regs[2], 2);
+            assert_eq!(cpu.instructions_retired(), 5);
+            (cpu.cycle(), cpu.branch_mispredicts())
+        };
+
+        let (cycle, mispredicts) = run(None);
+        assert_eq!(mispredicts, 0);
+        assert_eq!(run(Some(false)), (cycle, 0));
+        // the wrong path `addi a0` is flushed and `addi x2` is fetched again,
+        // which costs exactly one cycle.
+        assert_eq!(run(Some(true)), (cycle + 1, 1));
+    }
+
+    #[test]
+    fn test_timeline() {
+        let test_str = include_str!("../tests/matrix.s");
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        let mut cpu = CpuState::default();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        let timeline = cpu.timeline();
+        assert_eq!(timeline.len() as u32, cpu.instructions_retired());
+        assert!(timeline.windows(2).all(|w| w[0].issue <= w[1].issue));
+        for inst in &timeline {
+            let execute = inst.execute.unwrap();
+            assert!(execute > inst.issue);
+            assert_eq!(inst.write_back, Some(execute + 2));
+        }
+        assert_eq!(timeline.last().unwrap().write_back, Some(cpu.cycle()));
+    }
+
+    #[test]
+    fn test_branch_predictor_matrix() {
+        let test_str = include_str!("../tests/matrix.s");
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+
+        let mut baseline = CpuState::default();
+        baseline.load(&program).unwrap();
+        run_to_exit(&mut baseline);
+
+        let mut cpu = CpuState::default();
+        cpu.set_branch_predictor(Some(6)).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.regs.regs, baseline.regs.regs);
+        assert_eq!(cpu.mem.data, baseline.mem.data);
+        assert_eq!(cpu.instructions_retired(), baseline.instructions_retired());
+        assert!(cpu.branch_mispredicts() < baseline.control_hazard());
+    }
+
+    #[test]
+    fn test_exit_after_stall() {
+        let words = [
+            0x01100513, // addi a0, x0, 17
+            0x00002003, // lw x0, 0(x0)
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 0);
+        assert_eq!(cpu.instructions_retired(), 3);
+    }
+
+    #[test]
+    fn test_store_width() {
+        let test_str = r"
+        .globl main
+        .data
+        val: .word 0x11223344 0x11223344 0x11223344
+        .text
+        main:
+        addi x1, x0, 171
+        addi x3, x0, -1
+        sb x1, 1(x0)
+        sh x3, 6(x0)
+        sw x3, 8(x0)
+        lw x4, 0(x0)
+        lw x5, 4(x0)
+        lw x6, 8(x0)
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.regs[4], 0x1122ab44);
+        assert_eq!(cpu.regs[5], 0xffff3344);
+        assert_eq!(cpu.regs[6], 0xffffffff);
+    }
+
+    #[test]
+    fn test_ecall_exit() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        addi a7, x0, 93
+        addi a0, x0, 5
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+
+        let code = run_to_exit(&mut cpu);
+        assert_eq!(code, 5);
+
+        // an unknown service is reported, and running on resumes after it.
+        let words = [
+            0x00100893, // addi a7, x0, 1
+            0x00700513, // addi a0, x0, 7
+            0x00000073, // ecall
+            0x05d00893, // addi a7, x0, 93
+            0x00000073, // ecall
+        ];
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&words, 0).unwrap();
+        let state = loop {
+            match cpu.step().unwrap() {
+                RunState::Running => {}
+                state => break state,
+            }
+        };
+        assert_eq!(state, RunState::EcallUnknown { a7: 1, a0: 7 });
+        let state = loop {
+            match cpu.step().unwrap() {
+                RunState::Running => {}
+                state => break state,
+            }
+        };
+        assert_eq!(state, RunState::Exit(7));
+    }
+
+    #[test]
+    fn test_lui_and_auipc() {
+        let test_str = r"
+        .globl main
+        .text
+        main:
+        lui x1, 0x12345
+        auipc x2, 0
+        auipc x3, 1
+        addi a0, x0, 17
+        ecall
+        ";
+        let mut cpu = CpuState::default();
+        let program = Program::from_buffer(test_str.as_bytes()).unwrap();
+        cpu.load(&program).unwrap();
+        run_to_exit(&mut cpu);
+
+        assert_eq!(cpu.regs[1], 0x12345000);
+        assert_eq!(cpu.regs[2], 4);
+        assert_eq!(cpu.regs[3], 0x1008);
+    }
+
+    #[test]
+    fn test_cycle_count() {
+        let mut cpu = CpuState::default();
+        cpu.load_instructions(&[0x00000073], 0).unwrap(); // ecall
+        cpu.regs.set(10, 17);
+        assert_eq!(cpu.cycle(), 0);
+
+        let mut state = cpu.step().unwrap();
+        while let RunState::Running = state {
+            state = cpu.step().unwrap();
+        }
+
+        // a single instruction takes one cycle per pipeline stage.
+        assert!(matches!(state, RunState::Exit(0)));
+        assert_eq!(cpu.cycle(), 5);
+        assert_eq!(cpu.instructions_retired(), 1);
     }
 }
@@ -0,0 +1,125 @@
+use alloc::string::String;
+use core::fmt::{self, Display};
+
+/// Everything that can go wrong assembling, loading or running a program.
+/// `Display` gives the messages printed by the command line tools.
+#[derive(Clone, PartialEq, Debug)]
+pub enum RvError {
+    /// The source couldn't be read.
+    Io(String),
+    /// `line` is the 1-based source line, if the error is tied to one.
+    Assemble {
+        line: Option<usize>,
+        msg: String,
+    },
+    /// A binary image whose size isn't a whole number of words.
+    InvalidImage(usize),
+    /// A load base which isn't word aligned.
+    MisalignedBase(u32),
+    /// The image doesn't fit in the memory left from where it is loaded.
+    ImageTooLarge {
+        words: usize,
+        cap: usize,
+    },
+    /// A memory size which isn't a whole number of words, or outside of
+    /// `min..=max` bytes.
+    InvalidMemorySize {
+        size: u32,
+        min: u32,
+        max: u32,
+    },
+    /// A branch predictor table size, in bits, outside of 1-20.
+    InvalidPredictor(u32),
+    /// A cache whose line count or line size isn't a power of two, or with
+    /// more than 65536 lines.
+    InvalidCache {
+        lines: u32,
+        line_size: u32,
+    },
+    Decode(u32),
+    DecodeCompressed(u16),
+    /// A load, store or fetch outside of the memory.
+    MemoryFault(u32),
+    /// A jump or branch to `target` which isn't aligned to an instruction.
+    MisalignedFetch {
+        target: u32,
+    },
+    /// A signed overflow with overflow traps, `inst` is the disassembled
+    /// instruction at `pc`.
+    Overflow {
+        pc: u32,
+        inst: String,
+    },
+    /// A division by zero with division by zero traps.
+    DivideByZero {
+        pc: u32,
+        inst: String,
+    },
+    /// A load or store to text with split memory.
+    TextAccess {
+        addr: u32,
+        inst: String,
+    },
+    TooManyCycles,
+    UnknownEcall {
+        a7: u32,
+        a0: u32,
+    },
+}
+
+impl Display for RvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RvError::Io(msg) => write!(f, "{}", msg),
+            RvError::Assemble { msg, .. } => write!(f, "{}", msg),
+            RvError::InvalidImage(size) => {
+                write!(f, "image size {} is not a multiple of 4 bytes", size)
+            }
+            RvError::MisalignedBase(base) => {
+                write!(f, "load base {:#x} is not word aligned", base)
+            }
+            RvError::ImageTooLarge { words, cap } => write!(
+                f,
+                "program image ({} words) exceeds memory ({} words)",
+                words, cap
+            ),
+            RvError::InvalidMemorySize { size, min, max } => write!(
+                f,
+                "invalid memory size {}, it should be a multiple of 4 from {} to {}",
+                size, min, max
+            ),
+            RvError::InvalidPredictor(bits) => {
+                write!(f, "predictor table bits {} out of range 1-20", bits)
+            }
+            RvError::InvalidCache { lines, line_size } => write!(
+                f,
+                "cache lines ({}) and line size ({}) must be powers of two, with at most 65536 lines",
+                lines, line_size
+            ),
+            RvError::Decode(word) => write!(f, "Invalid instruction: {:08x}", word),
+            RvError::DecodeCompressed(parcel) => {
+                write!(f, "Invalid compressed instruction: {:04x}", parcel)
+            }
+            RvError::MisalignedFetch { target } => {
+                write!(f, "instruction address misaligned: {:#x}", target)
+            }
+            RvError::Overflow { pc, inst } => {
+                write!(f, "signed overflow in {} at {:#x}", inst, pc)
+            }
+            RvError::DivideByZero { pc, inst } => {
+                write!(f, "division by zero in {} at {:#x}", inst, pc)
+            }
+            RvError::TextAccess { addr, inst } => {
+                write!(f, "data access to text address {:#x} in {}", addr, inst)
+            }
+            RvError::MemoryFault(addr) => write!(f, "memory access out of range: {:#x}", addr),
+            RvError::TooManyCycles => write!(f, "too many cycles"),
+            RvError::UnknownEcall { a7, a0 } => {
+                write!(f, "unknown ecall: a7 = {}, a0 = {}", a7, a0)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RvError {}
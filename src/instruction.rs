@@ -1,4 +1,16 @@
-use std::fmt::{self, Display};
+use super::{alu::AluType, error::RvError};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+};
+use core::fmt::{self, Display};
+
+const REG_NAME: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
 
 #[derive(Clone)]
 pub struct Instruction {
@@ -9,6 +21,7 @@ pub struct Instruction {
     rd: u32,
     imm: u32,
     reg_write: bool,
+    compressed: bool,
 }
 
 #[derive(Clone, PartialEq, Debug, Copy)]
@@ -21,24 +34,6 @@ enum InstType {
     J,
 }
 
-#[derive(Clone, PartialEq, Debug, Copy)]
-pub(crate) enum AluType {
-    Add = 0,
-    Sll = 1,
-    Slt = 2,
-    Sltu = 3,
-    Xor = 4,
-    Srl = 5,
-    Or = 6,
-    And = 7,
-    Mul = 8,
-    Mulh = 9,
-    Mulhu = 11,
-    Sub = 12,
-    Sra = 13,
-    Bsel = 15,
-}
-
 #[derive(Clone, PartialEq, Debug, Copy)]
 pub(crate) enum WBType {
     Mem,
@@ -59,24 +54,47 @@ impl Instruction {
         Self::from_binary(0x00000033).unwrap() // add x0, x0, x0
     }
 
-    pub fn from_binary(binary: u32) -> Result<Self, String> {
+    pub fn from_binary(binary: u32) -> Result<Self, RvError> {
+        // the low 16 bits are a compressed instruction, which is decoded as
+        // the 32-bit one it expands to.
+        if binary & 0x3 != 0x3 {
+            let mut inst = Self::from_binary(expand_compressed(binary & 0xffff)?)?;
+            inst.compressed = true;
+            return Ok(inst);
+        }
+
         let inst_type = match binary & 0x7f {
             0x33 => InstType::R,
-            0x03 | 0x13 | 0x67 | 0x73 => InstType::I,
+            0x03 | 0x0f | 0x13 | 0x67 | 0x73 => InstType::I,
             0x23 => InstType::S,
             0x63 => InstType::B,
             0x37 | 0x17 => InstType::U,
             0x6f => InstType::J,
-            _ => return Err(format!("Invalid instruction: {:08x}", binary)),
+            _ => return Err(RvError::Decode(binary)),
         };
 
+        // funct7 only picks `sub`, `sra`, `srai` and the M extension, other
+        // values aren't instructions.
+        let funct3 = (binary >> 12) & 0x7;
+        let funct7 = binary >> 25;
+        let valid = match binary & 0x7f {
+            0x33 => matches!((funct7, funct3), (0x00 | 0x01, _) | (0x20, 0 | 5)),
+            0x13 if funct3 == 1 => funct7 == 0x00,
+            0x13 if funct3 == 5 => funct7 == 0x00 || funct7 == 0x20,
+            _ => true,
+        };
+        if !valid {
+            return Err(RvError::Decode(binary));
+        }
+
         let rs1 = (binary >> 15) & 0x1f;
         let rs2 = (binary >> 20) & 0x1f;
         let rd = (binary >> 7) & 0x1f;
         let reg_write = matches!(
             inst_type,
             InstType::R | InstType::I | InstType::U | InstType::J
-        ) && rd != 0;
+        ) && rd != 0
+            && binary & 0x7f != 0x0f; // fence
 
         let imm = match inst_type {
             InstType::I => sign_extend(binary >> 20, 12),
@@ -107,9 +125,23 @@ impl Instruction {
             rd,
             imm,
             reg_write,
+            compressed: false,
         })
     }
 
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Bytes the instruction takes in memory, 2 if it is compressed.
+    pub fn size(&self) -> u32 {
+        if self.compressed {
+            2
+        } else {
+            4
+        }
+    }
+
     pub fn is_jump(&self) -> bool {
         match self.inst_type {
             InstType::B => true,
@@ -119,6 +151,26 @@ impl Instruction {
         }
     }
 
+    pub fn is_branch(&self) -> bool {
+        self.inst_type == InstType::B
+    }
+
+    pub fn is_jal(&self) -> bool {
+        self.inst_type == InstType::J
+    }
+
+    pub fn is_csr(&self) -> bool {
+        (self.binary & 0x7f) == 0x73 && (self.binary >> 12) & 0x7 != 0
+    }
+
+    pub(crate) fn csr(&self) -> u32 {
+        self.binary >> 20
+    }
+
+    pub fn is_jalr(&self) -> bool {
+        self.inst_type == InstType::I && (self.binary & 0x7f) == 0x67
+    }
+
     pub fn rs1(&self) -> u32 {
         self.rs1
     }
@@ -143,13 +195,21 @@ impl Instruction {
         matches!(self.inst_type, InstType::R)
     }
 
-    pub(crate) fn alu_op(&self) -> AluType {
+    /// The operation the ALU does for this instruction, see `alu`.
+    pub fn alu_op(&self) -> AluType {
         match self.inst_type {
             InstType::R => {
                 let mut code = (self.binary >> 12) & 0x7;
-                code |= ((self.binary >> 30) & 0x1) * 0b1100;
-                code |= ((self.binary >> 25) & 0x1) * 0b1000;
-                code.into()
+                if (self.binary >> 25) & 0x1 == 1 {
+                    // the M extension: mul, mulh, mulhsu, mulhu are 8..=11,
+                    // div, divu, rem, remu are 16..=19.
+                    code += if code < 4 { 0b1000 } else { 0b1100 };
+                } else {
+                    code |= ((self.binary >> 30) & 0x1) * 0b1100;
+                }
+                // `from_binary` only lets through funct7 values with an
+                // operation.
+                code.try_into().unwrap_or(AluType::Add)
             }
             InstType::I if (self.binary & 0x7f) == 0x3 => AluType::Add,
             InstType::I => {
@@ -157,10 +217,11 @@ impl Instruction {
                 if code == 0b101 {
                     code |= ((self.binary >> 30) & 0x1) << 3;
                 }
-                code.into()
+                code.try_into().unwrap_or(AluType::Add)
             }
-            InstType::U => (((self.binary >> 5) & 0x1) * 0xf).into(),
-            _ => 0.into(),
+            InstType::U if (self.binary & 0x7f) == 0x37 => AluType::Bsel, // lui
+            InstType::U => AluType::Add,                                  // auipc
+            _ => AluType::Add,
         }
     }
 
@@ -202,6 +263,11 @@ impl Instruction {
         }
     }
 
+    // number of bytes accessed by a load or store.
+    pub(crate) fn mem_width(&self) -> u32 {
+        1 << ((self.binary >> 12) & 0x3)
+    }
+
     pub(crate) fn mem_op(&self) -> MemType {
         match self.inst_type {
             InstType::I => {
@@ -238,87 +304,121 @@ impl Instruction {
 
     // todo: print more user friendly info
     pub fn debug(&self) -> String {
-        // disassemble
+        self.disassemble(|reg| format!("x{}", reg), |imm| (imm as i32).to_string())
+    }
+
+    /// Disassemble with ABI register names, printing branch and jump targets
+    /// as the label at `pc + imm` if `symbols` has one, otherwise as the address.
+    pub fn debug_pretty(&self, pc: u32, symbols: &BTreeMap<u32, String>) -> String {
+        self.disassemble(
+            |reg| REG_NAME[reg as usize & 0x1f].to_string(),
+            |imm| {
+                let target = pc.wrapping_add(imm);
+                symbols
+                    .get(&target)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{:#x}", target))
+            },
+        )
+    }
+
+    fn disassemble<R, T>(&self, reg: R, target: T) -> String
+    where
+        R: Fn(u32) -> String,
+        T: Fn(u32) -> String,
+    {
+        let (rd, rs1, rs2) = (reg(self.rd), reg(self.rs1), reg(self.rs2));
         let inst = match self.inst_type {
             InstType::R => {
                 let func3 = (self.binary >> 12) & 0x7;
                 let func7 = (self.binary >> 25) & 0x7f;
                 match (func3, func7) {
-                    (0, 0) => format!("add x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (0, 0x20) => format!("sub x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (1, 0) => format!("sll x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (2, 0) => format!("slt x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (3, 0) => format!("sltu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (4, 0) => format!("xor x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (5, 0) => format!("srl x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (5, 0x20) => format!("sra x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (6, 0) => format!("or x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (7, 0) => format!("and x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (0, 1) => format!("mul x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (1, 1) => format!("mulh x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (2, 1) => format!("mulhsu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (3, 1) => format!("mulhu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (4, 1) => format!("div x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (5, 1) => format!("divu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (6, 1) => format!("rem x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
-                    (7, 1) => format!("remu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+                    (0, 0) => format!("add {}, {}, {}", rd, rs1, rs2),
+                    (0, 0x20) => format!("sub {}, {}, {}", rd, rs1, rs2),
+                    (1, 0) => format!("sll {}, {}, {}", rd, rs1, rs2),
+                    (2, 0) => format!("slt {}, {}, {}", rd, rs1, rs2),
+                    (3, 0) => format!("sltu {}, {}, {}", rd, rs1, rs2),
+                    (4, 0) => format!("xor {}, {}, {}", rd, rs1, rs2),
+                    (5, 0) => format!("srl {}, {}, {}", rd, rs1, rs2),
+                    (5, 0x20) => format!("sra {}, {}, {}", rd, rs1, rs2),
+                    (6, 0) => format!("or {}, {}, {}", rd, rs1, rs2),
+                    (7, 0) => format!("and {}, {}, {}", rd, rs1, rs2),
+                    (0, 1) => format!("mul {}, {}, {}", rd, rs1, rs2),
+                    (1, 1) => format!("mulh {}, {}, {}", rd, rs1, rs2),
+                    (2, 1) => format!("mulhsu {}, {}, {}", rd, rs1, rs2),
+                    (3, 1) => format!("mulhu {}, {}, {}", rd, rs1, rs2),
+                    (4, 1) => format!("div {}, {}, {}", rd, rs1, rs2),
+                    (5, 1) => format!("divu {}, {}, {}", rd, rs1, rs2),
+                    (6, 1) => format!("rem {}, {}, {}", rd, rs1, rs2),
+                    (7, 1) => format!("remu {}, {}, {}", rd, rs1, rs2),
                     _ => format!("unknown"),
                 }
             }
             InstType::I => {
+                let imm = self.imm as i32;
                 let opcode = self.binary & 0x7f;
                 let func3 = (self.binary >> 12) & 0x7;
                 match (opcode, func3) {
-                    (0x13, 0) => format!("addi x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 1) => format!("slli x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 2) => format!("slti x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 3) => format!("sltiu x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 4) => format!("xori x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 5) => format!("srli x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 6) => format!("ori x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x13, 7) => format!("andi x{}, x{}, {}", self.rd, self.rs1, self.imm),
-                    (0x3, 0) => format!("lb x{}, {}(x{})", self.rd, self.imm, self.rs1),
-                    (0x3, 1) => format!("lh x{}, {}(x{})", self.rd, self.imm, self.rs1),
-                    (0x3, 2) => format!("lw x{}, {}(x{})", self.rd, self.imm, self.rs1),
-                    (0x3, 4) => format!("lbu x{}, {}(x{})", self.rd, self.imm, self.rs1),
-                    (0x3, 5) => format!("lhu x{}, {}(x{})", self.rd, self.imm, self.rs1),
-                    (0x67, 0) => format!("jalr x{}, {}(x{})", self.rd, self.imm, self.rs1),
+                    (0x13, 0) => format!("addi {}, {}, {}", rd, rs1, imm),
+                    (0x13, 1) => format!("slli {}, {}, {}", rd, rs1, self.imm),
+                    (0x13, 2) => format!("slti {}, {}, {}", rd, rs1, imm),
+                    (0x13, 3) => format!("sltiu {}, {}, {}", rd, rs1, imm),
+                    (0x13, 4) => format!("xori {}, {}, {}", rd, rs1, imm),
+                    (0x13, 5) => format!("srli {}, {}, {}", rd, rs1, self.imm),
+                    (0x13, 6) => format!("ori {}, {}, {}", rd, rs1, imm),
+                    (0x13, 7) => format!("andi {}, {}, {}", rd, rs1, imm),
+                    (0x3, 0) => format!("lb {}, {}({})", rd, imm, rs1),
+                    (0x3, 1) => format!("lh {}, {}({})", rd, imm, rs1),
+                    (0x3, 2) => format!("lw {}, {}({})", rd, imm, rs1),
+                    (0x3, 4) => format!("lbu {}, {}({})", rd, imm, rs1),
+                    (0x3, 5) => format!("lhu {}, {}({})", rd, imm, rs1),
+                    (0x67, 0) => format!("jalr {}, {}({})", rd, imm, rs1),
+                    (0x0f, 0) => "fence".to_string(),
+                    (0x0f, 1) => "fence.i".to_string(),
+                    (0x73, 1) => format!("csrrw {}, {}, {}", rd, self.csr(), rs1),
+                    (0x73, 2) => format!("csrrs {}, {}, {}", rd, self.csr(), rs1),
+                    (0x73, 3) => format!("csrrc {}, {}, {}", rd, self.csr(), rs1),
+                    (0x73, 5) => format!("csrrwi {}, {}, {}", rd, self.csr(), self.rs1),
+                    (0x73, 6) => format!("csrrsi {}, {}, {}", rd, self.csr(), self.rs1),
+                    (0x73, 7) => format!("csrrci {}, {}, {}", rd, self.csr(), self.rs1),
+                    (0x73, 0) if self.imm == 1 => format!("ebreak"),
                     (0x73, 0) => format!("ecall"),
-                    (0x73, 1) => format!("ebreak"),
                     _ => format!("unknown"),
                 }
             }
             InstType::S => {
+                let imm = self.imm as i32;
                 let func3 = (self.binary >> 12) & 0x7;
                 match func3 {
-                    0 => format!("sb x{}, {}(x{})", self.rs2, self.imm, self.rs1),
-                    1 => format!("sh x{}, {}(x{})", self.rs2, self.imm, self.rs1),
-                    2 => format!("sw x{}, {}(x{})", self.rs2, self.imm, self.rs1),
+                    0 => format!("sb {}, {}({})", rs2, imm, rs1),
+                    1 => format!("sh {}, {}({})", rs2, imm, rs1),
+                    2 => format!("sw {}, {}({})", rs2, imm, rs1),
                     _ => format!("unknown"),
                 }
             }
             InstType::B => {
                 let func3 = (self.binary >> 12) & 0x7;
                 match func3 {
-                    0 => format!("beq x{}, x{}, {}", self.rs1, self.rs2, self.imm),
-                    1 => format!("bne x{}, x{}, {}", self.rs1, self.rs2, self.imm),
-                    4 => format!("blt x{}, x{}, {}", self.rs1, self.rs2, self.imm),
-                    5 => format!("bge x{}, x{}, {}", self.rs1, self.rs2, self.imm),
-                    6 => format!("bltu x{}, x{}, {}", self.rs1, self.rs2, self.imm),
-                    7 => format!("bgeu x{}, x{}, {}", self.rs1, self.rs2, self.imm),
+                    0 => format!("beq {}, {}, {}", rs1, rs2, target(self.imm)),
+                    1 => format!("bne {}, {}, {}", rs1, rs2, target(self.imm)),
+                    4 => format!("blt {}, {}, {}", rs1, rs2, target(self.imm)),
+                    5 => format!("bge {}, {}, {}", rs1, rs2, target(self.imm)),
+                    6 => format!("bltu {}, {}, {}", rs1, rs2, target(self.imm)),
+                    7 => format!("bgeu {}, {}, {}", rs1, rs2, target(self.imm)),
                     _ => format!("unknown"),
                 }
             }
             InstType::U => {
-                let opcode = self.binary & 0x7;
+                let opcode = self.binary & 0x7f;
                 match opcode {
-                    0x37 => format!("lui x{}, {}", self.rd, self.imm),
-                    0x17 => format!("auipc x{}, {}", self.rd, self.imm),
+                    // the 20-bit field as written in the source, not shifted.
+                    0x37 => format!("lui {}, {:#x}", rd, self.imm >> 12),
+                    0x17 => format!("auipc {}, {:#x}", rd, self.imm >> 12),
                     _ => format!("unknown"),
                 }
             }
             InstType::J => {
-                format!("jal x{}, {}", self.rd, self.imm)
+                format!("jal {}, {}", rd, target(self.imm))
             }
         };
 
@@ -342,29 +442,94 @@ impl Display for Instruction {
     }
 }
 
-impl From<u32> for AluType {
-    fn from(value: u32) -> Self {
-        match value {
-            0 => AluType::Add,
-            1 => AluType::Sll,
-            2 => AluType::Slt,
-            3 => AluType::Sltu,
-            4 => AluType::Xor,
-            5 => AluType::Srl,
-            6 => AluType::Or,
-            7 => AluType::And,
-            8 => AluType::Mul,
-            9 => AluType::Mulh,
-            11 => AluType::Mulhu,
-            12 => AluType::Sub,
-            13 => AluType::Sra,
-            15 => AluType::Bsel,
-            _ => panic!("Invalid alu type: {}", value),
+// the 32-bit equivalent of the supported RVC instructions, `c.addi`, `c.mv`,
+// `c.lw`, `c.sw`, `c.jal` and `c.beqz`.
+fn expand_compressed(c: u32) -> Result<u32, RvError> {
+    let bit = |i: u32| (c >> i) & 1;
+    let funct3 = (c >> 13) & 0x7;
+    // `rd'`, `rs1'` and `rs2'` only name x8..=x15.
+    let rs1_short = ((c >> 7) & 0x7) + 8;
+    let rs2_short = ((c >> 2) & 0x7) + 8;
+    // offset of c.lw and c.sw, uimm[5:3|2|6]
+    let mem_offset = (((c >> 10) & 0x7) << 3) | (bit(6) << 2) | (bit(5) << 6);
+
+    let inst = match (c & 0x3, funct3) {
+        // c.lw -> lw rd', uimm(rs1')
+        (0b00, 0b010) => {
+            (mem_offset << 20) | (rs1_short << 15) | (0x2 << 12) | (rs2_short << 7) | 0x03
         }
-    }
+        // c.sw -> sw rs2', uimm(rs1')
+        (0b00, 0b110) => {
+            ((mem_offset >> 5) << 25)
+                | (rs2_short << 20)
+                | (rs1_short << 15)
+                | (0x2 << 12)
+                | ((mem_offset & 0x1f) << 7)
+                | 0x23
+        }
+        // c.addi -> addi rd, rd, nzimm
+        (0b01, 0b000) => {
+            let rd = (c >> 7) & 0x1f;
+            let imm = sign_extend((bit(12) << 5) | ((c >> 2) & 0x1f), 6);
+            (imm << 20) | (rd << 15) | (rd << 7) | 0x13
+        }
+        // c.jal -> jal ra, offset
+        (0b01, 0b001) => {
+            let offset = sign_extend(
+                (bit(12) << 11)
+                    | (bit(11) << 4)
+                    | (((c >> 9) & 0x3) << 8)
+                    | (bit(8) << 10)
+                    | (bit(7) << 6)
+                    | (bit(6) << 7)
+                    | (((c >> 3) & 0x7) << 1)
+                    | (bit(2) << 5),
+                12,
+            );
+            ((offset >> 20 & 1) << 31)
+                | ((offset >> 1 & 0x3ff) << 21)
+                | ((offset >> 11 & 1) << 20)
+                | (offset & 0xff000)
+                | (1 << 7)
+                | 0x6f
+        }
+        // c.beqz -> beq rs1', zero, offset
+        (0b01, 0b110) => {
+            let offset = sign_extend(
+                (bit(12) << 8)
+                    | (((c >> 10) & 0x3) << 3)
+                    | (((c >> 5) & 0x3) << 6)
+                    | (((c >> 3) & 0x3) << 1)
+                    | (bit(2) << 5),
+                9,
+            );
+            ((offset >> 12 & 1) << 31)
+                | ((offset >> 5 & 0x3f) << 25)
+                | (rs1_short << 15)
+                | ((offset >> 1 & 0xf) << 8)
+                | ((offset >> 11 & 1) << 7)
+                | 0x63
+        }
+        // c.mv -> add rd, zero, rs2
+        (0b10, 0b100) if bit(12) == 0 && (c >> 2) & 0x1f != 0 => {
+            let rd = (c >> 7) & 0x1f;
+            let rs2 = (c >> 2) & 0x1f;
+            (rs2 << 20) | (rd << 7) | 0x33
+        }
+        _ => return Err(RvError::DecodeCompressed(c as u16)),
+    };
+
+    Ok(inst)
 }
 
 fn sign_extend(value: u32, bits: u32) -> u32 {
+    // there is no sign bit to extend from a zero-width or full-width field.
+    if bits == 0 {
+        return 0;
+    } else if bits >= 32 {
+        return value;
+    }
+
     let shift = 32 - bits;
     let sign = (value >> (bits - 1)) & 1;
     let mask = ((1 << shift) - 1) << bits;
@@ -509,6 +674,16 @@ mod tests {
         assert!(Instruction::from_binary(0x00000000).is_err()); // invalid instruction
     }
 
+    #[test]
+    fn test_sign_extend() {
+        assert_eq!(sign_extend(0x7ff, 12), 0x7ff);
+        assert_eq!(sign_extend(0x800, 12), 0xffff_f800);
+        assert_eq!(sign_extend(0x1, 1), 0xffff_ffff);
+        assert_eq!(sign_extend(0x8000_0000, 32), 0x8000_0000);
+        assert_eq!(sign_extend(0x1234_5678, 32), 0x1234_5678);
+        assert_eq!(sign_extend(0x1234_5678, 0), 0);
+    }
+
     #[test]
     fn test_branch() {
         let inst = Instruction::from_binary(0x00000063).unwrap(); // beq x0, x0, 0
@@ -555,4 +730,116 @@ mod tests {
         assert_eq!(inst.branch(u32::MAX, 0), true);
         assert_eq!(inst.branch(0, u32::MAX), false);
     }
+
+    #[test]
+    fn test_m_extension_alu_op() {
+        // op x1, x2, x3 with funct7 = 1 and each funct3
+        let cases = [
+            (0x023100b3, AluType::Mul),
+            (0x023110b3, AluType::Mulh),
+            (0x023120b3, AluType::Mulhsu),
+            (0x023130b3, AluType::Mulhu),
+            (0x023140b3, AluType::Div),
+            (0x023150b3, AluType::Divu),
+            (0x023160b3, AluType::Rem),
+            (0x023170b3, AluType::Remu),
+        ];
+        for (binary, op) in cases {
+            let inst = Instruction::from_binary(binary).unwrap();
+            assert_eq!(inst.alu_op(), op, "{}", inst.debug());
+        }
+    }
+
+    #[test]
+    fn test_compressed() {
+        let cases = [
+            (0x0505, 0x00150513), // c.addi a0, 1 -> addi a0, a0, 1
+            (0x157d, 0xfff50513), // c.addi a0, -1 -> addi a0, a0, -1
+            (0x852e, 0x00b00533), // c.mv a0, a1 -> add a0, zero, a1
+            (0x4048, 0x00442503), // c.lw a0, 4(s0) -> lw a0, 4(s0)
+            (0xc4a8, 0x04a4a423), // c.sw a0, 72(s1) -> sw a0, 72(s1)
+            (0x2011, 0x004000ef), // c.jal 4 -> jal ra, 4
+            (0x3ff5, 0xffdff0ef), // c.jal -4 -> jal ra, -4
+            (0xc501, 0x00050463), // c.beqz a0, 8 -> beq a0, zero, 8
+            (0xdd6d, 0xfe050de3), // c.beqz a0, -6 -> beq a0, zero, -6
+        ];
+        for (binary, expanded) in cases {
+            let inst = Instruction::from_binary(binary).unwrap();
+            assert!(inst.is_compressed());
+            assert_eq!(inst.size(), 2);
+            assert_eq!(inst.binary, expanded, "{:04x}: {}", binary, inst.debug());
+        }
+
+        // only the low half is decoded.
+        let inst = Instruction::from_binary(0x12340505).unwrap();
+        assert_eq!(inst.binary, 0x00150513);
+
+        assert!(Instruction::from_binary(0x0000).is_err()); // illegal
+        assert!(Instruction::from_binary(0x8002).is_err()); // c.jr ra
+        assert_eq!(Instruction::nop().size(), 4);
+    }
+
+    #[test]
+    fn test_invalid_funct7() {
+        assert!(Instruction::from_binary(0x40002033).is_err()); // slt with funct7 0x20
+        assert!(Instruction::from_binary(0x40006033).is_err()); // or with funct7 0x20
+        assert!(Instruction::from_binary(0x08000033).is_err()); // add with funct7 0x04
+        assert!(Instruction::from_binary(0x40001013).is_err()); // slli with funct7 0x20
+        assert!(Instruction::from_binary(0x02005013).is_err()); // srli with funct7 0x01
+        assert!(Instruction::from_binary(0x40000033).is_ok()); // sub
+        assert!(Instruction::from_binary(0x40005013).is_ok()); // srai
+    }
+
+    #[test]
+    fn test_random_words() {
+        // xorshift32, any word decodes to an error or an instruction whose
+        // methods don't panic.
+        let mut x: u32 = 0x1234_5678;
+        let symbols = BTreeMap::new();
+        for _ in 0..5000 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            let Ok(inst) = Instruction::from_binary(x) else {
+                continue;
+            };
+            inst.alu_op();
+            inst.write_back();
+            inst.mem_op();
+            inst.mem_width();
+            inst.branch(x, !x);
+            inst.debug_pretty(x, &symbols);
+            if inst.binary & 0x7f == 0x33 {
+                assert!(!inst.debug().starts_with("unknown"), "{:08x}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn test_debug_pretty() {
+        let inst = Instruction::from_binary(0xfe000ce3).unwrap(); // beq x0, x0, -8
+        let mut symbols = BTreeMap::new();
+        assert_eq!(inst.debug_pretty(0x20, &symbols), "beq zero, zero, 0x18");
+
+        symbols.insert(0x18, "loop".to_string());
+        assert_eq!(inst.debug_pretty(0x20, &symbols), "beq zero, zero, loop");
+
+        let inst = Instruction::from_binary(0x00a00133).unwrap(); // add x2, x0, x10
+        assert_eq!(inst.debug_pretty(0, &symbols), "add sp, zero, a0");
+        assert_eq!(inst.debug(), "add x2, x0, x10");
+    }
+
+    #[test]
+    fn test_debug_signed_imm() {
+        let inst = Instruction::from_binary(0xfff00093).unwrap(); // addi x1, x0, -1
+        assert_eq!(inst.debug(), "addi x1, x0, -1");
+        let inst = Instruction::from_binary(0xfe208ce3).unwrap(); // beq x1, x2, -8
+        assert_eq!(inst.debug(), "beq x1, x2, -8");
+        let inst = Instruction::from_binary(0xfe512e23).unwrap(); // sw x5, -4(x2)
+        assert_eq!(inst.debug(), "sw x5, -4(x2)");
+        let inst = Instruction::from_binary(0xfffff0b7).unwrap(); // lui x1, 0xfffff
+        assert_eq!(inst.debug(), "lui x1, 0xfffff");
+        let inst = Instruction::from_binary(0x12345117).unwrap(); // auipc x2, 0x12345
+        assert_eq!(inst.debug(), "auipc x2, 0x12345");
+    }
 }
@@ -1,7 +1,24 @@
-pub use assembler::Program;
-pub use cpu::{CpuState, RunState};
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub use alu::{alu, AluType};
+#[cfg(feature = "std")]
+pub use assembler::{Endian, ListingLine, Program};
+#[cfg(feature = "std")]
+pub use cpu::{
+    run, CacheConfig, CpuState, CycleTrace, Cycles, InstTimeline, Producer, RunState, RunSummary,
+    Stage,
+};
+pub use error::RvError;
 pub use instruction::Instruction;
 
+mod alu;
+#[cfg(feature = "std")]
 mod assembler;
+#[cfg(feature = "std")]
 mod cpu;
+mod error;
+// the write back and memory helpers are only used by the pipeline.
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
 mod instruction;
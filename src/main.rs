@@ -1,10 +1,11 @@
 use clap::Parser;
 use lazy_static::lazy_static;
-use rvsim::{CpuState, Program, RunState};
+use rvsim::{CacheConfig, CpuState, Program, RunState, RvError};
 use std::{
+    collections::HashSet,
     error::Error,
-    io,
-    path::PathBuf,
+    io::{self, BufRead},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering::Relaxed},
         Arc,
@@ -14,30 +15,122 @@ use std::{
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Input assembly file
+    /// Input assembly file, `-` reads it from stdin
     path: PathBuf,
 
     /// Print pipeline info for each cycle
     #[arg(short, long)]
     verbose: bool,
 
+    /// With --verbose, only print the registers which are not zero or being written
+    #[arg(long)]
+    compact: bool,
+
+    /// Print only what changed in each cycle, the pc and the registers
+    #[arg(long, conflicts_with = "verbose")]
+    verbose_diff: bool,
+
     /// Print analysis info
     #[arg(short, long)]
     analysis: bool,
 
-    /// Step running
+    /// Step running, an empty line steps one cycle
     #[arg(short, long)]
     step: bool,
+
+    /// Only assemble and validate the program, without running it
+    #[arg(short, long)]
+    check: bool,
+
+    /// Predict branches with a table of 2^BITS 2-bit counters
+    #[arg(short, long, value_name = "BITS", value_parser = clap::value_parser!(u32).range(1..=20))]
+    predict: Option<u32>,
+
+    /// Extra cycles each instruction spends in fetch
+    #[arg(long, default_value_t = 0)]
+    fetch_delay: u32,
+
+    /// Extra cycles an instruction using a load's result stalls
+    #[arg(long, default_value_t = 0)]
+    load_delay: u32,
+
+    /// Put a direct-mapped data cache of LINES lines in front of memory
+    #[arg(long, value_name = "LINES", value_parser = clap::value_parser!(u32).range(1..=65536))]
+    cache: Option<u32>,
+
+    /// Bytes in each cache line
+    #[arg(long, default_value_t = 16)]
+    cache_line: u32,
+
+    /// Extra cycles a load or store which misses the cache stalls
+    #[arg(long, default_value_t = 10)]
+    miss_penalty: u32,
+
+    /// Stop when add, addi, sub or mul overflow as signed numbers
+    #[arg(long)]
+    trap_on_overflow: bool,
+
+    /// Stop when div, divu, rem or remu divide by zero
+    #[arg(long)]
+    trap_on_div_zero: bool,
+
+    /// Bytes of memory
+    #[arg(long, value_name = "BYTES", default_value_t = 32 * 1024)]
+    memory_size: u32,
+
+    /// Keep instructions in their own memory, loads and stores to text fail
+    #[arg(long)]
+    split_memory: bool,
+
+    /// Print the registers as JSON when the program ends
+    #[arg(long)]
+    dump_regs: bool,
+
+    /// Only print a `cycles=N exit=C` summary line, for benchmarking
+    #[arg(short, long, conflicts_with_all = ["verbose", "verbose_diff", "step"])]
+    quiet: bool,
+
+    /// Print the --quiet summary as JSON
+    #[arg(long, requires = "quiet")]
+    json: bool,
 }
 
 lazy_static! {
     static ref ARGS: Args = Args::parse();
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let program = Program::from_file(&ARGS.path)?;
-    let mut app = AppState::new(&program);
-    let mut buf = String::new();
+// print errors with their message rather than their `Debug` form.
+fn main() {
+    if let Err(err) = try_main() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn try_main() -> Result<(), Box<dyn Error>> {
+    let program = if ARGS.path == Path::new("-") {
+        if ARGS.step {
+            return Err("the program and the step commands can't both come from stdin".into());
+        }
+        Program::from_buffer(io::stdin().lock())
+    } else {
+        Program::from_file(&ARGS.path)
+    }?;
+
+    if ARGS.check {
+        let diagnostics = program.validate();
+        if diagnostics.is_empty() {
+            println!("{}: ok", ARGS.path.display());
+            return Ok(());
+        }
+
+        for diagnostic in diagnostics {
+            eprintln!("{}: {}", ARGS.path.display(), diagnostic);
+        }
+        std::process::exit(1);
+    }
+
+    let mut app = AppState::new(&program)?;
 
     let quit = Arc::new(AtomicBool::new(false));
     ctrlc::set_handler(move || {
@@ -55,15 +148,21 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     if ARGS.step {
-        while let Ok(_) = io::stdin().read_line(&mut buf) {
-            app.step()?;
-        }
+        app.repl(&program, io::stdin().lock())?;
     } else {
         app.run()?;
     }
 
+    if ARGS.quiet {
+        println!("{}", app.summary());
+    }
+
     if ARGS.analysis {
-        app.analysis();
+        app.analysis(&program);
+    }
+
+    if ARGS.dump_regs {
+        println!("{}", app.registers_json());
     }
 
     Ok(())
@@ -71,63 +170,334 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 struct AppState {
     cpu: CpuState,
+    breakpoints: HashSet<u32>,
+    exit: Option<u32>,
+    // the state after the previous cycle, for --verbose-diff.
+    last: Snapshot,
+}
+
+/// What `--verbose-diff` compares between cycles.
+struct Snapshot {
+    pc: u32,
+    regs: [u32; 32],
+}
+
+impl Snapshot {
+    fn of(cpu: &CpuState) -> Self {
+        Snapshot {
+            pc: cpu.pc(),
+            regs: std::array::from_fn(|i| cpu.reg(i as u32)),
+        }
+    }
+
+    // one line for each change from `self` to `next`.
+    fn diff(&self, next: &Snapshot) -> Vec<String> {
+        let mut lines = Vec::new();
+        if next.pc != self.pc {
+            lines.push(format!("pc: {:08x} -> {:08x}", self.pc, next.pc));
+        }
+        for (i, (old, new)) in self.regs.iter().zip(next.regs.iter()).enumerate() {
+            if old != new {
+                lines.push(format!(
+                    "{:>3}: {:08x} -> {:08x}",
+                    format!("x{}", i),
+                    old,
+                    new
+                ));
+            }
+        }
+        lines
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step,
+    Continue,
+    Break(String),
+    Regs,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        None | Some("s") | Some("step") => Command::Step,
+        Some("c") | Some("continue") => Command::Continue,
+        Some("b") | Some("break") => match words.next() {
+            Some(label) => Command::Break(label.to_string()),
+            None => return Err("usage: break <label>".to_string()),
+        },
+        Some("r") | Some("regs") => Command::Regs,
+        Some("q") | Some("quit") => Command::Quit,
+        Some(word) => return Err(format!("unknown command: {}", word)),
+    };
+
+    match words.next() {
+        Some(word) => Err(format!("unexpected argument: {}", word)),
+        None => Ok(command),
+    }
 }
 
 impl AppState {
-    fn new(program: &Program) -> Self {
+    fn new(program: &Program) -> Result<Self, Box<dyn Error>> {
         let mut cpu = CpuState::default();
-        cpu.load(&program);
+        cpu.set_fetch_delay(ARGS.fetch_delay);
+        cpu.set_load_delay(ARGS.load_delay);
+        cpu.set_branch_predictor(ARGS.predict)?;
+        cpu.set_cache(ARGS.cache.map(|lines| CacheConfig {
+            lines,
+            line_size: ARGS.cache_line,
+            miss_penalty: ARGS.miss_penalty,
+        }))?;
+        cpu.set_split_memory(ARGS.split_memory);
+        cpu.set_trap_on_overflow(ARGS.trap_on_overflow);
+        cpu.set_trap_on_div_zero(ARGS.trap_on_div_zero);
+        cpu.set_memory_size(ARGS.memory_size)?;
+        cpu.set_compact_display(ARGS.compact);
+        cpu.load(program)?;
 
-        AppState { cpu }
+        Ok(AppState {
+            last: Snapshot::of(&cpu),
+            cpu,
+            breakpoints: HashSet::new(),
+            exit: None,
+        })
     }
 
-    fn step(&mut self) -> Result<(), String> {
-        self.cpu.step()?;
+    fn step(&mut self) -> Result<RunState, RvError> {
+        let state = self.cpu.step()?;
         if ARGS.verbose {
             println!("{}", self.cpu);
         }
+        if ARGS.verbose_diff {
+            let next = Snapshot::of(&self.cpu);
+            println!("-- cycle {}", self.cpu.cycle());
+            for line in self.last.diff(&next) {
+                println!("{}", line);
+            }
+            self.last = next;
+        }
 
-        Ok(())
+        Ok(state)
     }
 
-    fn run(&mut self) -> Result<(), String> {
-        loop {
-            let state = self.cpu.step()?;
-            if ARGS.verbose {
-                println!("{}", self.cpu);
+    fn repl<R: BufRead>(&mut self, program: &Program, input: R) -> Result<(), RvError> {
+        for line in input.lines() {
+            let line = line.map_err(|e| RvError::Io(e.to_string()))?;
+            let command = match parse_command(&line) {
+                Ok(command) => command,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+
+            match command {
+                Command::Step => match self.step()? {
+                    RunState::Running => {}
+                    RunState::Exit(code) => {
+                        self.exit = Some(code);
+                        print_exit(code);
+                        return Ok(());
+                    }
+                    RunState::Break => println!("Program break!"),
+                    RunState::EcallUnknown { a7, a0 } => {
+                        return Err(RvError::UnknownEcall { a7, a0 })
+                    }
+                },
+                Command::Continue => loop {
+                    let retired = self.cpu.instructions_retired();
+                    match self.step()? {
+                        RunState::Running => {}
+                        RunState::Exit(code) => {
+                            self.exit = Some(code);
+                            print_exit(code);
+                            return Ok(());
+                        }
+                        RunState::Break => {
+                            println!("Program break!");
+                            break;
+                        }
+                        RunState::EcallUnknown { a7, a0 } => {
+                            return Err(RvError::UnknownEcall { a7, a0 })
+                        }
+                    }
+
+                    // only stop once the instruction before the breakpoint has
+                    // completed, and not again on the bubbles after it.
+                    if self.cpu.instructions_retired() != retired
+                        && self.breakpoints.contains(&self.cpu.pc())
+                    {
+                        println!("Breakpoint at {:08x}", self.cpu.pc());
+                        break;
+                    }
+                },
+                Command::Break(label) => match program.addr_of(&label) {
+                    Some(addr) => {
+                        self.breakpoints.insert(addr);
+                        println!("Breakpoint {} at {:08x}", label, addr);
+                    }
+                    None => println!("unknown label: {}", label),
+                },
+                Command::Regs => {
+                    for i in 0..32 {
+                        print!("{:>3}: {:08x}, ", format!("x{}", i), self.cpu.reg(i));
+                        if i % 8 == 7 {
+                            println!();
+                        }
+                    }
+                }
+                Command::Quit => break,
             }
+        }
 
-            match state {
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), RvError> {
+        loop {
+            match self.step()? {
                 RunState::Running => {}
                 RunState::Exit(code) => {
-                    if code == 0 {
-                        println!("Succesfully exit!");
-                    } else {
-                        println!("Exit with code {}!", code);
+                    self.exit = Some(code);
+                    if !ARGS.quiet {
+                        print_exit(code);
                     }
                     break;
                 }
+                // nobody is there to press enter when benchmarking.
+                RunState::Break if ARGS.quiet => {}
                 RunState::Break => {
                     println!("Program break!");
                     println!("Press Enter to continue.");
 
                     let mut buf = String::new();
-                    io::stdin().read_line(&mut buf).map_err(|e| e.to_string())?;
+                    io::stdin()
+                        .read_line(&mut buf)
+                        .map_err(|e| RvError::Io(e.to_string()))?;
                 }
+                RunState::EcallUnknown { a7, a0 } => return Err(RvError::UnknownEcall { a7, a0 }),
             }
         }
 
         Ok(())
     }
 
-    fn analysis(&self) {
+    fn analysis(&self, program: &Program) {
+        let cycle = self.cpu.cycle();
+        let retired = self.cpu.instructions_retired();
+        let stall = self.cpu.data_hazard()
+            + self.cpu.control_hazard()
+            + self.cpu.branch_mispredicts()
+            + self.cpu.fetch_stall()
+            + self.cpu.cache_stall();
+        let ipc = if cycle == 0 {
+            0.0
+        } else {
+            retired as f64 / cycle as f64
+        };
+
         println!("========== Analysis ==========");
-        println!("All Cycle: {}", self.cpu.cycle() - 1);
-        println!("Data Hazard: {}", self.cpu.data_hazard());
-        println!("Control Hazard: {}", self.cpu.control_hazard());
-        println!(
-            "Stall Cycle: {}",
-            self.cpu.data_hazard() + self.cpu.control_hazard()
+        println!("{:<16}{:>14}", "All Cycle", cycle);
+        println!("{:<16}{:>14}", "Instructions", retired);
+        println!("{:<16}{:>14.3}", "IPC", ipc);
+        println!("{:<16}{:>14}", "Data Hazard", self.cpu.data_hazard());
+        println!("{:<16}{:>14}", "Control Hazard", self.cpu.control_hazard());
+        println!("{:<16}{:>14}", "Mispredict", self.cpu.branch_mispredicts());
+        println!("{:<16}{:>14}", "Fetch Stall", self.cpu.fetch_stall());
+        if ARGS.cache.is_some() {
+            println!("{:<16}{:>14}", "Cache Hit", self.cpu.cache_hits());
+            println!("{:<16}{:>14}", "Cache Miss", self.cpu.cache_misses());
+            println!("{:<16}{:>14}", "Cache Stall", self.cpu.cache_stall());
+        }
+        println!("{:<16}{:>14}", "Stall Cycle", stall);
+        println!("{:<16}{:>14}", "Text Bytes", program.text_size());
+        println!("{:<16}{:>14}", "Data Bytes", program.data_size());
+        println!("{:<16}{:>14}", "Bss Bytes", program.bss_size());
+        println!("{:<16}{:>14}", "Stack Bytes", self.cpu.stack_usage());
+    }
+
+    // the exit code is `null`, or `none`, if the program never exited.
+    fn summary(&self) -> String {
+        if ARGS.json {
+            let exit = self
+                .exit
+                .map_or("null".to_string(), |code| code.to_string());
+            format!("{{\"cycles\": {}, \"exit\": {}}}", self.cpu.cycle(), exit)
+        } else {
+            let exit = self
+                .exit
+                .map_or("none".to_string(), |code| code.to_string());
+            format!("cycles={} exit={}", self.cpu.cycle(), exit)
+        }
+    }
+
+    fn registers_json(&self) -> String {
+        let regs = (0..32)
+            .map(|i| format!("\"x{}\": {}", i, self.cpu.reg(i)))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", regs.join(", "))
+    }
+}
+
+fn print_exit(code: u32) {
+    if code == 0 {
+        println!("Succesfully exit!");
+    } else {
+        println!("Exit with code {}!", code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_diff() {
+        let program = Program::from_buffer(
+            ".globl main\n.text\nmain:\naddi x5, x0, 10\naddi a7, x0, 93\necall\n".as_bytes(),
+        )
+        .unwrap();
+        let mut cpu = CpuState::default();
+        cpu.load(&program).unwrap();
+
+        let mut last = Snapshot::of(&cpu);
+        let mut diffs = Vec::new();
+        while let RunState::Running = cpu.step().unwrap() {
+            let next = Snapshot::of(&cpu);
+            diffs.push(last.diff(&next));
+            last = next;
+        }
+
+        // the pipeline is filling up, nothing has been written yet.
+        assert!(diffs[0].is_empty());
+        assert!(diffs[3].is_empty());
+        assert_eq!(
+            diffs[4],
+            ["pc: 00000000 -> 00000004", " x5: 00000000 -> 0000000a"]
+        );
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let script = "\nstep\nbreak main\nb loop\ncontinue\nregs\nbreak\njump\ns 2\nq";
+        let commands = script.lines().map(parse_command).collect::<Vec<_>>();
+
+        assert_eq!(
+            commands,
+            vec![
+                Ok(Command::Step),
+                Ok(Command::Step),
+                Ok(Command::Break("main".to_string())),
+                Ok(Command::Break("loop".to_string())),
+                Ok(Command::Continue),
+                Ok(Command::Regs),
+                Err("usage: break <label>".to_string()),
+                Err("unknown command: jump".to_string()),
+                Err("unexpected argument: 2".to_string()),
+                Ok(Command::Quit),
+            ]
         );
     }
 }
@@ -0,0 +1,42 @@
+use std::process::Command;
+
+#[test]
+fn test_as_check() {
+    for path in [
+        "tests/1.s",
+        "tests/2.s",
+        "tests/3.s",
+        "tests/dot.s",
+        "tests/matrix.s",
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_rvsim-as"))
+            .args(["--check", path])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        assert!(output.status.success(), "{}: {}", path, stdout);
+        assert!(!stdout.contains("mismatch"));
+        assert!(!stdout.is_empty());
+    }
+}
+
+#[test]
+fn test_as_endian() {
+    let out = std::env::temp_dir().join(format!("rvsim-as-{}.bin", std::process::id()));
+    let out_arg = out.to_str().unwrap();
+
+    // the first word of tests/1.s is `addi x1, x0, 1`, 0x00100093.
+    for (endian, first) in [
+        ("little", [0x93, 0x00, 0x10, 0x00]),
+        ("big", [0x00, 0x10, 0x00, 0x93]),
+    ] {
+        let status = Command::new(env!("CARGO_BIN_EXE_rvsim-as"))
+            .args(["--endian", endian, "-o", out_arg, "tests/1.s"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read(&out).unwrap()[..4], first, "{}", endian);
+    }
+    std::fs::remove_file(&out).unwrap();
+}
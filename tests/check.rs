@@ -0,0 +1,54 @@
+use std::{fs, process::Command};
+
+fn check(path: &str) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rvsim"))
+        .args(["--check", path])
+        .output()
+        .unwrap();
+
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn test_check_valid() {
+    for path in [
+        "tests/1.s",
+        "tests/2.s",
+        "tests/3.s",
+        "tests/dot.s",
+        "tests/matrix.s",
+    ] {
+        let (ok, stderr) = check(path);
+        assert!(ok, "{}: {}", path, stderr);
+        assert!(stderr.is_empty());
+    }
+}
+
+#[test]
+fn test_check_invalid() {
+    let dir = std::env::temp_dir().join(format!("rvsim-check-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // the entry points at data instead of code.
+    let bad_entry = dir.join("bad_entry.s");
+    fs::write(
+        &bad_entry,
+        ".globl msg\n.data\nmsg: .word 1\n.text\necall\n",
+    )
+    .unwrap();
+    let (ok, stderr) = check(bad_entry.to_str().unwrap());
+    assert!(!ok);
+    assert!(stderr.contains("program entry 00000000 is not an instruction"));
+
+    // the program doesn't assemble at all.
+    let bad_label = dir.join("bad_label.s");
+    fs::write(&bad_label, ".globl main\n.text\nmain:\njal x0, nowhere\n").unwrap();
+    let (ok, stderr) = check(bad_label.to_str().unwrap());
+    assert!(!ok);
+    assert!(stderr.contains("undefined label nowhere"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
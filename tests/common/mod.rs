@@ -0,0 +1,20 @@
+use std::{
+    fs,
+    process::{Command, Output},
+};
+
+/// Run `rvsim` with `args` on `source`, written to a temporary `name`.s.
+pub fn run_rvsim(name: &str, source: &str, args: &[&str]) -> Output {
+    let dir = std::env::temp_dir().join(format!("rvsim-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{}.s", name));
+    fs::write(&path, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rvsim"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    output
+}
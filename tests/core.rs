@@ -0,0 +1,23 @@
+// only the parts built without the `std` feature, run with
+// `cargo test --no-default-features --lib --test core`.
+use rvsim::{alu, AluType, Instruction, RvError};
+
+#[test]
+fn test_decode_and_execute() {
+    // addi a0, a0, -1
+    let inst = Instruction::from_binary(0xfff50513).unwrap();
+    assert_eq!(inst.rd(), 10);
+    assert_eq!(inst.rs1(), 10);
+    assert_eq!(inst.alu_op(), AluType::Add);
+    assert_eq!(alu(5, inst.imm(), inst.alu_op()), 4);
+    assert_eq!(inst.debug_pretty(0, &Default::default()), "addi a0, a0, -1");
+
+    // mulhu a0, a1, a2
+    let inst = Instruction::from_binary(0x02c5b533).unwrap();
+    assert_eq!(alu(0xffff_ffff, 0xffff_ffff, inst.alu_op()), 0xffff_fffe);
+
+    assert_eq!(
+        Instruction::from_binary(0xffff_ffff).err(),
+        Some(RvError::Decode(0xffff_ffff))
+    );
+}
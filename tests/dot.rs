@@ -0,0 +1,38 @@
+use rvsim::{CpuState, Program, RunState};
+use std::path::Path;
+
+fn run(cpu: &mut CpuState) -> u32 {
+    let program = Program::from_file(Path::new("tests/dot.s")).unwrap();
+    cpu.load(&program).unwrap();
+
+    loop {
+        match cpu.step().unwrap() {
+            RunState::Running => {}
+            RunState::Exit(code) => return code,
+            RunState::Break => panic!("unexpected ebreak"),
+            RunState::EcallUnknown { a7, .. } => panic!("unexpected ecall {}", a7),
+        }
+    }
+}
+
+#[test]
+fn test_dot_product() {
+    let mut cpu = CpuState::default();
+    assert_eq!(run(&mut cpu), 54);
+    // one load-use stall per iteration, and one per taken branch.
+    assert_eq!(cpu.data_hazard(), 5);
+    assert_eq!(cpu.control_hazard(), 5);
+}
+
+#[test]
+fn test_dot_product_timing() {
+    let mut predicted = CpuState::default();
+    predicted.set_branch_predictor(Some(4)).unwrap();
+    assert_eq!(run(&mut predicted), 54);
+    assert_eq!(predicted.control_hazard(), 0);
+
+    let mut slow = CpuState::default();
+    slow.set_load_delay(2);
+    assert_eq!(run(&mut slow), 54);
+    assert_eq!(slow.data_hazard(), 5 * 3);
+}
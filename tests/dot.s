@@ -0,0 +1,26 @@
+.globl main
+
+.data
+x: .word 1 -2 3 4 5
+y: .word 6 7 -8 9 10
+
+.text
+# =======================================================
+# FUNCTION: main
+# exit(x . y), which should be 54
+main:
+	addi a0, x0, 0
+	la a1, x
+	la a2, y
+	addi a3, x0, 5
+loop:
+	lw t0, 0(a1)
+	lw t1, 0(a2)
+	mul t2, t0, t1
+	add a0, a0, t2
+	addi a1, a1, 4
+	addi a2, a2, 4
+	addi a3, a3, -1
+	bne a3, x0, loop
+	addi a7, x0, 93
+	ecall
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+
+mod common;
+
+#[test]
+fn test_dump_regs() {
+    let output = common::run_rvsim(
+        "dump",
+        ".globl main\n.text\nmain:\naddi x1, x0, 1\naddi x2, x0, 2\naddi x3, x0, 3\n\
+         addi x4, x0, 4\naddi x5, x0, 5\naddi a0, x0, 17\necall\n",
+        &["--dump-regs"],
+    );
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let json = stdout.lines().find(|line| line.starts_with('{')).unwrap();
+    let regs = json
+        .trim_matches(|c| c == '{' || c == '}')
+        .split(", ")
+        .map(|pair| {
+            let (name, value) = pair.split_once(": ").unwrap();
+            (
+                name.trim_matches('"').to_string(),
+                value.parse::<u32>().unwrap(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    assert_eq!(regs.len(), 32);
+    for i in 1..=5 {
+        assert_eq!(regs[&format!("x{}", i)], i);
+    }
+    assert_eq!(regs["x10"], 17);
+}
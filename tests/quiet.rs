@@ -0,0 +1,25 @@
+mod common;
+
+#[test]
+fn test_quiet() {
+    let run = |args: &[&str]| {
+        let output = common::run_rvsim(
+            "quiet",
+            ".globl main\n.text\nmain:\naddi a0, x0, 3\nebreak\naddi a7, x0, 93\necall\n",
+            args,
+        );
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = run(&["--quiet"]);
+    assert_eq!(stdout.lines().collect::<Vec<_>>(), ["cycles=8 exit=3"]);
+
+    let stdout = run(&["--quiet", "--json"]);
+    assert_eq!(stdout, "{\"cycles\": 8, \"exit\": 3}\n");
+
+    let stdout = run(&["--quiet", "--analysis"]);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("cycles=8 exit=3"));
+    assert_eq!(lines.next(), Some("========== Analysis =========="));
+}